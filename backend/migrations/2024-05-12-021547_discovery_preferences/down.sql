@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users DROP COLUMN seeking_genders;
+ALTER TABLE users DROP COLUMN max_distance;
+ALTER TABLE users DROP COLUMN age_max;
+ALTER TABLE users DROP COLUMN age_min;
\ No newline at end of file
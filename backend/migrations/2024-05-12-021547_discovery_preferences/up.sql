@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN age_min INT;
+ALTER TABLE users ADD COLUMN age_max INT;
+ALTER TABLE users ADD COLUMN max_distance INT; /* km */
+ALTER TABLE users ADD COLUMN seeking_genders TEXT; /* comma separated, NULL for any */
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX users_geohash_idx;
+ALTER TABLE users DROP COLUMN geohash;
\ No newline at end of file
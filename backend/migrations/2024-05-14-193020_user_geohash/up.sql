@@ -0,0 +1,5 @@
+-- Your SQL goes here
+/* geohash of (latitude, longitude), maintained by the backend on write */
+ALTER TABLE users ADD COLUMN geohash TEXT;
+
+CREATE INDEX users_geohash_idx ON users(geohash);
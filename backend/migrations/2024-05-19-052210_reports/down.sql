@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE blocks;
+DROP TABLE reports;
\ No newline at end of file
@@ -0,0 +1,34 @@
+-- Your SQL goes here
+CREATE TABLE reports (
+	
+	id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	reporter TEXT NOT NULL,
+	reported TEXT NOT NULL,
+	
+	reason TEXT NOT NULL,
+	details TEXT,
+	message_ids TEXT, /* comma separated */
+	
+	timestamp TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	
+	FOREIGN KEY (reporter) REFERENCES users(id) ON DELETE CASCADE,
+	FOREIGN KEY (reported) REFERENCES users(id) ON DELETE CASCADE
+	
+);
+
+CREATE INDEX reports_reported_idx ON reports(reported);
+
+CREATE TABLE blocks (
+	
+	blocker TEXT NOT NULL,
+	blocked TEXT NOT NULL,
+	
+	timestamp TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	
+	PRIMARY KEY (blocker, blocked),
+	FOREIGN KEY (blocker) REFERENCES users(id) ON DELETE CASCADE,
+	FOREIGN KEY (blocked) REFERENCES users(id) ON DELETE CASCADE
+	
+);
+
+CREATE INDEX blocks_blocked_idx ON blocks(blocked);
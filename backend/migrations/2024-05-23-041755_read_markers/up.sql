@@ -0,0 +1,17 @@
+-- Your SQL goes here
+CREATE TABLE read_markers (
+	
+	user1 TEXT NOT NULL,
+	user2 TEXT NOT NULL,
+	reader INT NOT NULL, /* same encoding as messages.sender */
+	
+	/* last message read; unread counting goes by seq, added in message_seq */
+	message_id TEXT NOT NULL,
+	timestamp TEXT NOT NULL,
+	
+	PRIMARY KEY (user1, user2, reader),
+	FOREIGN KEY (user1) REFERENCES users(id) ON DELETE CASCADE,
+	FOREIGN KEY (user2) REFERENCES users(id) ON DELETE CASCADE,
+	CHECK (user1 < user2)
+	
+);
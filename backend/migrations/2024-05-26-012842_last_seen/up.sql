@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN last_seen TEXT; /* maintained by the backend on connect/disconnect */
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE device_tokens (
+	
+	/* FCM registration token; moves to whoever registered it last */
+	token TEXT PRIMARY KEY NOT NULL,
+	user_id TEXT NOT NULL,
+	
+	timestamp TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	
+	FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+	
+);
+
+CREATE INDEX device_tokens_user_idx ON device_tokens(user_id);
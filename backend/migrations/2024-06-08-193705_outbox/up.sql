@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE outbox (
+	
+	/* AUTOINCREMENT so sequence numbers are never reused once acknowledged */
+	seq INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	user_id TEXT NOT NULL,
+	
+	payload TEXT NOT NULL, /* serialized OutgoingMessage */
+	timestamp TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	
+	FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+	
+);
+
+CREATE INDEX outbox_user_idx ON outbox(user_id, seq);
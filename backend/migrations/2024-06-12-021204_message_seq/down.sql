@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE read_markers DROP COLUMN seq;
+
+DROP INDEX messages_idx;
+CREATE INDEX messages_idx ON messages(user1, user2, timestamp);
+
+DROP TRIGGER messages_seq;
+DROP TABLE message_seq;
+ALTER TABLE messages DROP COLUMN seq;
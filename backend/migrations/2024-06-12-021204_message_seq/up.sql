@@ -0,0 +1,26 @@
+-- Your SQL goes here
+/* Insertion order. Timestamps only have second resolution and ids are random, so neither orders messages. */
+ALTER TABLE messages ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+UPDATE messages SET seq = rowid;
+
+/* Last seq handed out. Never goes backwards, unlike rowid, which VACUUM can renumber and deletes can free up. */
+CREATE TABLE message_seq (
+	seq INTEGER NOT NULL
+);
+INSERT INTO message_seq (seq) SELECT COALESCE(MAX(seq), 0) FROM messages;
+
+CREATE TRIGGER messages_seq AFTER INSERT ON messages
+BEGIN
+	UPDATE message_seq SET seq = seq + 1;
+	UPDATE messages SET seq = (SELECT seq FROM message_seq) WHERE id = NEW.id;
+END;
+
+DROP INDEX messages_idx;
+CREATE INDEX messages_idx ON messages(user1, user2, seq);
+
+/* seq of the last message read, for unread counting */
+ALTER TABLE read_markers ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+UPDATE read_markers SET seq = COALESCE(
+	(SELECT messages.seq FROM messages WHERE messages.id = read_markers.message_id),
+	0
+);
@@ -1,11 +1,17 @@
 
 use crate::Id;
+use crate::geo;
 use crate::schema;
+use crate::metrics::MetricsState;
+use crate::queue::{QueueStrategy, DefaultQueueStrategy, Candidate};
 use crate::models::{
 	
 	User,
+	Preferences,
 	Match,
 	ChatMessage,
+	ReadState,
+	Report,
 	
 	Profile,
 	Sender,
@@ -14,21 +20,95 @@ use crate::models::{
 };
 
 use diesel::prelude::*;
-use diesel::{insert_into, update};
+use diesel::{insert_into, update, delete};
+use diesel::sqlite::Sqlite;
+use diesel::sql_types::{Bool, Nullable, Text, Integer};
+use diesel::result::{Error as DieselError, DatabaseErrorKind};
 use deadpool_diesel::sqlite::{Runtime, Manager, Pool};
+use deadpool_diesel::{PoolError, InteractError};
 
-use std::fmt::Display;
+use tracing::{debug, error};
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+type CellFilter = Box<dyn BoxableExpression<schema::users::table, Sqlite, SqlType = Nullable<Bool>>>;
+
+sql_function!(fn substr(text: Nullable<Text>, start: Integer, length: Integer) -> Nullable<Text>);
+
+// How long a query waits for a free connection before giving up
+const POOL_WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum DbError {
+	// No connection freed up within POOL_WAIT_TIMEOUT, or the pool is gone
+	PoolExhausted,
+	NotFound,
+	// Unique, foreign key, not null or check constraint
+	ConstraintViolation(String),
+	// A row couldn't be converted to or from its Rust type
+	Serialization(String),
+	// Anything else, including queries that panicked
+	Query(String)
+}
+
+impl fmt::Display for DbError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			DbError::PoolExhausted => write!(f, "connection pool exhausted"),
+			DbError::NotFound => write!(f, "not found"),
+			DbError::ConstraintViolation(err) => write!(f, "constraint violation: {}", err),
+			DbError::Serialization(err) => write!(f, "serialization error: {}", err),
+			DbError::Query(err) => write!(f, "query error: {}", err)
+		}
+	}
+}
+
+impl From<DieselError> for DbError {
+	fn from(err: DieselError) -> Self {
+		match err {
+			DieselError::NotFound => DbError::NotFound,
+			DieselError::DatabaseError(
+				DatabaseErrorKind::UniqueViolation |
+				DatabaseErrorKind::ForeignKeyViolation |
+				DatabaseErrorKind::NotNullViolation |
+				DatabaseErrorKind::CheckViolation,
+				info
+			) => DbError::ConstraintViolation(info.message().to_string()),
+			DieselError::DeserializationError(err) | DieselError::SerializationError(err) =>
+				DbError::Serialization(err.to_string()),
+			err => DbError::Query(err.to_string())
+		}
+	}
+}
+
+impl From<PoolError> for DbError {
+	fn from(err: PoolError) -> Self {
+		match err {
+			PoolError::Timeout(_) | PoolError::Closed => DbError::PoolExhausted,
+			err => DbError::Query(err.to_string())
+		}
+	}
+}
+
+impl From<InteractError> for DbError {
+	fn from(err: InteractError) -> Self {
+		DbError::Query(err.to_string())
+	}
+}
 
 #[derive(Clone)]
 pub struct DatabaseState {
-	connections: Pool
+	connections: Pool,
+	queue_strategy: Arc<dyn QueueStrategy>,
+	metrics: MetricsState
 }
 
-
 impl DatabaseState {
 	
 	fn get_url() -> String {
-	
+		
 		use dotenvy::dotenv;
 		use std::env;
 		
@@ -37,94 +117,147 @@ impl DatabaseState {
 		
 	}
 	
-	pub fn new() -> Self {
+	pub fn new(metrics: MetricsState) -> Self {
+		Self::with_url(Self::get_url(), metrics)
+	}
+	
+	fn with_url(url: String, metrics: MetricsState) -> Self {
 		
-		let manager = Manager::new(Self::get_url(), Runtime::Tokio1);
+		let manager = Manager::new(url, Runtime::Tokio1);
 		let connections = Pool::builder(manager)
 			.max_size(8)
+			.wait_timeout(Some(POOL_WAIT_TIMEOUT))
+			.runtime(Runtime::Tokio1)
 			.build()
 			.expect("Error creating Sqlite connection pool");
 		
-		Self { connections }
+		let queue_strategy = Arc::new(DefaultQueueStrategy::from_env());
+		
+		Self { connections, queue_strategy, metrics }
 		
 	}
 	
-	fn strip<T>(option: Option<T>) -> Option<()> {
-		match option {
-			Some(_) => Some(()),
-			None => None
-		}
+	// A fresh, migrated database in the temp directory
+	#[cfg(test)]
+	pub fn temporary() -> Self {
+		
+		use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+		
+		const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+		
+		let path = std::env::temp_dir().join(format!("nemesis-test-{}.sqlite3", uuid::Uuid::new_v4()));
+		let url = path.to_string_lossy().to_string();
+		
+		let mut connection = SqliteConnection::establish(&url).expect("Error creating test database");
+		connection.run_pending_migrations(MIGRATIONS).expect("Error migrating test database");
+		
+		Self::with_url(url, MetricsState::new())
+		
 	}
-	fn users_to_profiles(users: Vec<User>) -> Vec<Profile> {
+	
+	// Pool usage only changes as queries come and go, so it's read when scraped
+	pub fn record_pool_status(&self) {
+		
+		let status = self.connections.status();
+		
+		self.metrics.pool_size.set(status.size as i64);
+		self.metrics.pool_available.set(status.available as i64);
+		self.metrics.pool_waiting.set(status.waiting as i64);
+		
+	}
+	
+	fn strip<T>(result: Result<T, DbError>) -> Result<(), DbError> {
+		result.map(|_| ())
+	}
+	fn users_to_profiles(users: Vec<User>, for_user: &User) -> Vec<Profile> {
 		
 		users
 			.into_iter()
-			.map(move |user| user.to_profile())
+			.map(move |user| user.to_profile(for_user))
 			.collect()
 		
 	}
 	
-	async fn execute_result<T, E, F>(&self, query: F) -> Option<Result<T, E>>
+	// `name` labels the query's latency metric
+	async fn execute_result<T, E, F>(&self, name: &'static str, query: F) -> Result<T, DbError>
 	where
 		F: Send + 'static + FnOnce(&mut SqliteConnection) -> Result<T, E>,
 		T: Send + 'static,
-		E: Send + 'static
+		E: Send + 'static + Into<DbError>
 	{
 		
+		let start = Instant::now();
 		let connection = self.connections.get().await;
+		let waited = start.elapsed();
+		self.metrics.pool_wait.observe(waited.as_secs_f64());
+		
+		let start = Instant::now();
+		let output = connection?.interact(query).await?;
+		let elapsed = start.elapsed();
+		self.metrics.query_duration.with_label_values(&[name]).observe(elapsed.as_secs_f64());
+		
+		// Lands in whichever handler span made the query
+		debug!(
+			query = name,
+			wait_ms = waited.as_secs_f64() * 1000.0,
+			elapsed_ms = elapsed.as_secs_f64() * 1000.0,
+			ok = output.is_ok(),
+			"query"
+		);
 		
-		match connection {
-			Err(err) => println!("{err}"),
-			Ok(connection) => {
-				match connection.interact(query).await {
-					Err(err) => println!("{err}"),
-					Ok(output) => return Some(output)
-				}
-			}
-		};
-		
-		None
+		output.map_err(Into::into)
 		
 	}
 	
-	async fn execute_expect<T, F, E>(&self, message: &'static str, query: F) -> Option<T>
+	async fn execute_expect<T, F, E>(&self, name: &'static str, message: &'static str, query: F) -> Result<T, DbError>
 	where
 		F: Send + 'static + FnOnce(&mut SqliteConnection) -> Result<T, E>,
 		T: Send + 'static,
-		E: Send + 'static + Display
+		E: Send + 'static + Into<DbError>
 	{
 		
-		match self.execute_result(query).await {
-			Some(Ok(output)) => Some(output),
-			Some(Err(err)) => {
-				println!("{message}: {err}");
-				None
-			},
-			_ => None
+		let result = self.execute_result(name, query).await;
+		
+		// Not finding something is usually expected, and left to the caller
+		if let Err(err) = &result {
+			if !matches!(err, DbError::NotFound) {
+				error!(%err, "{message}");
+			}
 		}
 		
+		result
+		
 	}
-	async fn execute<T, E, F>(&self, query: F) -> Option<T>
+	async fn execute<T, E, F>(&self, name: &'static str, query: F) -> Result<T, DbError>
 	where
 		F: Send + 'static + FnOnce(&mut SqliteConnection) -> Result<T, E>,
 		T: Send + 'static,
-		E: Send + 'static
+		E: Send + 'static + Into<DbError>
 	{
+		self.execute_result(name, query).await
+	}
+	
+	// Checks a pooled connection can still run a query at all
+	pub async fn ping(&self) -> Result<(), DbError> {
 		
-		match self.execute_result(query).await {
-			Some(Ok(output)) => Some(output),
-			_ => None
-		}
+		use diesel::sql_query;
+		
+		Self::strip(self.execute_expect(
+			"ping",
+			"Error pinging database",
+			move |connection| sql_query("SELECT 1").execute(connection)
+		).await)
 		
 	}
 	
-	pub async fn get_user(&self, user_id: &Id) -> Option<User> {
+	pub async fn get_user(&self, user_id: &Id) -> Result<User, DbError> {
 		
 		use schema::users;
 		
 		let user_id = user_id.clone();
 		
 		self.execute_expect(
+			"get_user",
 			"Error getting user",
 			move |connection|
 				users::table
@@ -135,13 +268,14 @@ impl DatabaseState {
 		
 	}
 	
-	pub async fn handle_autolikes(&self, user_id: &Id) -> Option<()> {
+	pub async fn handle_autolikes(&self, user_id: &Id) -> Result<(), DbError> {
 		
 		use schema::users;
 		use schema::matches;
 		
-		let results: (Option<Vec<String>>, Option<Vec<String>>, Option<Vec<String>>) = tokio::join!(
+		let results = tokio::join!(
 			self.execute_expect(
+				"get_autolike_ids",
 				"Error getting autoliking users",
 				move |connection| {
 					users::table
@@ -151,6 +285,7 @@ impl DatabaseState {
 				}
 			),
 			self.execute_expect(
+				"get_autodislike_ids",
 				"Error getting autoliking users",
 				move |connection| {
 					users::table
@@ -160,6 +295,7 @@ impl DatabaseState {
 				}
 			),
 			self.execute_expect(
+				"get_automatch_ids",
 				"Error getting automatch users",
 				move |connection| {
 					users::table
@@ -170,48 +306,46 @@ impl DatabaseState {
 			)
 		);
 		
-		if let (Some(autolike_ids), Some(autodislike_ids), Some(automatch_ids)) = results {
-			
-			let user_id = user_id.clone(); // genuinely no clue why this is necessary, but it works
-			Self::strip(
-				self.execute_expect(
-					"Error autoliking/disliking users",
-					move |connection| {
-						
-						//let user_id = user_id.clone();
-						//let user_id2 = user_id.clone();
-						let likes = autolike_ids
-							.into_iter()
-							.map(|id| Id::new(id))
-							.map(|id| Match::new(&id, &user_id,
-								MatchState::Pending(Sender::of(&id, &user_id))));
-						
-						let dislikes = autodislike_ids
-							.into_iter()
-							.map(|id| Id::new(id))
-							.map(|id| Match::new(&id, &user_id, MatchState::Dead));
-						
-						let matches = automatch_ids
-							.into_iter()
-							.map(|id| Id::new(id))
-							.map(|id| Match::new(&id, &user_id, MatchState::Active));
-						
-						insert_into(matches::table)
-							.values(
-								likes
-									.chain(dislikes)
-									.chain(matches)
-									.collect::<Vec<_>>()
-							)
-							.execute(connection)
-							
-					}
-				).await
-			)
-			
-		} else {
-			None
-		}
+		let (autolike_ids, autodislike_ids, automatch_ids) = (results.0?, results.1?, results.2?);
+		
+		let user_id = user_id.clone(); // genuinely no clue why this is necessary, but it works
+		Self::strip(
+			self.execute_expect(
+				"insert_automatches",
+				"Error autoliking/disliking users",
+				move |connection| {
+					
+					//let user_id = user_id.clone();
+					//let user_id2 = user_id.clone();
+					let likes = autolike_ids
+						.into_iter()
+						.map(Id::new)
+						.map(|id| Match::new(&id, &user_id,
+							MatchState::Pending(Sender::of(&id, &user_id))));
+					
+					let dislikes = autodislike_ids
+						.into_iter()
+						.map(Id::new)
+						.map(|id| Match::new(&id, &user_id, MatchState::Dead));
+					
+					let matches = automatch_ids
+						.into_iter()
+						.map(Id::new)
+						.map(|id| Match::new(&id, &user_id, MatchState::Active));
+					
+					insert_into(matches::table)
+						.values(
+							likes
+								.chain(dislikes)
+								.chain(matches)
+								.collect::<Vec<_>>()
+						)
+						.execute(connection)
+					
+				}
+			).await
+		)
+		
 		/*
 		let result = self.execute_expect();
 		
@@ -230,19 +364,20 @@ impl DatabaseState {
 		
 	}
 	
-	pub async fn read_user(&self, user_id: &Id) -> Option<User> {
+	pub async fn read_user(&self, user_id: &Id) -> Result<User, DbError> {
 		
 		use schema::users::{self, dsl::*};
 		
 		let result = self.get_user(user_id).await;
 		
-		if let Some(user) = result {
-			return Some(user);
+		if !matches!(result, Err(DbError::NotFound)) {
+			return result;
 		}
 		
 		// User doesn't exist, let's create it
 		let id_clone = user_id.clone();
 		let result = self.execute_expect(
+			"insert_user",
 			"Error inserting user on read",
 			move |connection|
 				insert_into(users::table)
@@ -252,87 +387,240 @@ impl DatabaseState {
 		
 		
 		
-		match result {
-			Some(_) => {
-				self.handle_autolikes(&user_id).await;
-				Some(User::new(user_id.clone()))
-			},
-			None => None
+		result?;
+		let _ = self.handle_autolikes(user_id).await;
+		
+		Ok(User::new(user_id.clone()))
+		
+	}
+	pub async fn write_user(&self, mut user: User) -> Result<(), DbError> {
+		
+		use schema::users;
+		
+		user.update_geohash();
+		
+		let updated = self.execute_expect(
+			"write_user",
+			"Error on user write", 
+			move |connection| {
+				update(users::table.find(&user.id))
+					.set(&user)
+					.execute(connection)
+		}).await?;
+		
+		// Users only exist once they've been read at least once
+		match updated {
+			0 => Err(DbError::NotFound),
+			_ => Ok(())
 		}
 		
 	}
-	pub async fn write_user(&self, user: User) -> Option<()> {
+	pub async fn write_preferences(&self, user_id: &Id, preferences: Preferences) -> Result<(), DbError> {
 		
 		use schema::users;
 		
-		Self::strip(
-			self.execute_expect(
-				"Error on user write", 
-				move |connection| {
-					update(users::table.find(&user.id))
-						.set(&user)
-						.execute(connection)
-			}).await
-		)
+		let user_id = user_id.clone();
+		
+		let updated = self.execute_expect(
+			"write_preferences",
+			"Error writing preferences",
+			move |connection|
+				update(users::table.find(&*user_id))
+					.set(&preferences)
+					.execute(connection)
+		).await?;
+		
+		match updated {
+			0 => Err(DbError::NotFound),
+			_ => Ok(())
+		}
 		
 	}
-	
-	pub async fn get_profile(&self, user_id: &Id) -> Option<Profile> {
+	// Users who set a location before geohashes existed, or were written to directly.
+	// Without one they never show up for viewers with a max distance.
+	pub async fn backfill_geohashes(&self) -> Result<usize, DbError> {
 		
-		self.get_user(&*user_id)
-			.await
-			.map(|user| user.to_profile())
+		use schema::users::{self, dsl};
+		
+		self.execute_expect(
+			"backfill_geohashes",
+			"Error backfilling geohashes",
+			move |connection| connection.transaction(|connection| {
+				
+				let missing = users::table
+					.filter(dsl::geohash.is_null())
+					.filter(dsl::latitude.is_not_null())
+					.filter(dsl::longitude.is_not_null())
+					.select(User::as_select())
+					.load::<User>(connection)?;
+				
+				let count = missing.len();
+				for mut user in missing {
+					user.update_geohash();
+					update(users::table.find(&user.id))
+						.set(dsl::geohash.eq(&user.geohash))
+						.execute(connection)?;
+				}
+				
+				Ok::<_, DieselError>(count)
+				
+			})
+		).await
 		
 	}
-	pub async fn get_queue_profiles(&self, user_id: &Id, blacklist: Option<Vec<String>>) -> Option<Vec<Profile>> {
+	
+	pub async fn get_queue_profiles(&self, user_id: &Id, blacklist: Option<Vec<String>>) -> Result<Vec<Profile>, DbError> {
 		
 		use schema::users::{self, dsl::*};
 		use schema::matches::{self, dsl::*};
+		use schema::blocks;
 		
 		let user_id = user_id.clone();
-		let result: Option<Vec<User>> = self.execute_expect(
+		let strategy = self.queue_strategy.clone();
+		let result: Result<(User, Vec<User>), DbError> = self.execute_expect(
+			"get_queue_profiles",
 			"Error getting candidate profiles",
 			move |connection| {
 				
+				let viewer = users::table
+					.select(User::as_select())
+					.find(&*user_id)
+					.first::<User>(connection)?;
+				
 				let two_liked_one = MatchState::Pending(Sender::Two);
 				let one_liked_two = MatchState::Pending(Sender::One);
 				
-				let ineligible_one = {
-					matches::table
+				// Subqueries can't be reused once built, so these make a fresh one per page
+				let ineligible = || {
+					
+					let ineligible_one = matches::table
 						.select(user2)
 						.filter(user1.eq(&*user_id))
-						.filter(state.ne(two_liked_one)) // allow unreciprocated likes from others
+						.filter(state.ne(&two_liked_one)); // allow unreciprocated likes from others
+					
+					let ineligible_two = matches::table
+						.select(user1)
+						.filter(user2.eq(&*user_id))
+						.filter(state.ne(&one_liked_two)); // allow unreciprocated likes from others
+					
+					let blocked_by = blocks::table
+						.select(blocks::blocked)
+						.filter(blocks::blocker.eq(&*user_id));
+					
+					let blocked_from = blocks::table
+						.select(blocks::blocker)
+						.filter(blocks::blocked.eq(&*user_id));
+					
+					ineligible_one
+						.union(ineligible_two)
+						.union(blocked_by)
+						.union(blocked_from)
+					
 				};
 				
-				let ineligible_two = {
+				// Unreciprocated likes from others, which get ranked up
+				let liked_viewer = || {
 					matches::table
-						.select(user1)
-						.filter(user2.eq(&*user_id))
-						.filter(state.ne(one_liked_two)) // allow unreciprocated likes from others
+						.select(user2)
+						.filter(user1.eq(&*user_id))
+						.filter(state.eq(&two_liked_one))
+						.union(
+							matches::table
+								.select(user1)
+								.filter(user2.eq(&*user_id))
+								.filter(state.eq(&one_liked_two)))
 				};
 				
-				let ineligible = ineligible_one.union(ineligible_two);
+				// Only look in the grid cells around the viewer when they've capped distance
+				let neighbourhood = viewer.location()
+					.zip(viewer.max_distance)
+					.and_then(|((lat, long), radius)| geo::neighbourhood(lat, long, radius as f64));
 				
-				// this one needs a strategy check
-				users::table
-					.select(User::as_select())
-					.filter(id.ne(&*user_id))
-					.filter(id.ne_all(ineligible))
-					.filter(id.ne_all(blacklist.unwrap_or_default()))
-					.limit(5)
-					.load::<User>(connection)
+				// Prefilter the viewer's own age range; everything else needs computing.
+				// Only the date portion, like User::age, so full timestamps compare the same.
+				let (born_after, born_before) = viewer.birth_date_range();
+				let birth_day = || substr(birth_date, 1, 10);
+				
+				let blacklist = blacklist.unwrap_or_default();
+				let limit = strategy.candidate_limit();
+				
+				let mut candidates_page = |offset: usize| {
+					
+					let mut query = users::table
+						.select((User::as_select(), id.eq_any(liked_viewer())))
+						.filter(id.ne(&*user_id))
+						.filter(id.ne_all(ineligible()))
+						.filter(id.ne_all(&blacklist))
+						.into_boxed();
+					
+					if let Some(cells) = &neighbourhood {
+						
+						// Range rather than LIKE so the geohash index gets used
+						let in_cells = cells
+							.iter()
+							.map(|cell| {
+								let end = format!("{cell}~");
+								Box::new(geohash.ge(cell.clone()).and(geohash.lt(end))) as CellFilter
+							})
+							.reduce(|cells, cell| Box::new(cells.or(cell)));
+						
+						if let Some(in_cells) = in_cells {
+							query = query.filter(in_cells);
+						}
+						
+					}
+					
+					if let Some(born_after) = &born_after {
+						query = query.filter(birth_day().gt(born_after));
+					}
+					if let Some(born_before) = &born_before {
+						query = query.filter(birth_day().le(born_before));
+					}
+					
+					// Whoever already liked the viewer goes first, since they rank
+					// highest anyway, then the most recently active
+					query
+						.order((id.eq_any(liked_viewer()).desc(), last_seen.desc(), id))
+						.limit(limit as i64)
+						.offset(offset as i64)
+						.load::<(User, bool)>(connection)
+					
+				};
+				
+				// Bounded before ranking. Preferences that can't be checked in SQL are
+				// checked a page at a time, so the pool only runs short if the candidates do.
+				let mut candidates = Vec::new();
+				let mut offset = 0;
+				while candidates.len() < limit {
+					
+					let page = candidates_page(offset)?;
+					let exhausted = page.len() < limit;
+					offset += page.len();
+					
+					candidates.extend(page
+						.into_iter()
+						.filter(|(candidate, _)| viewer.accepts_mutually(candidate))
+						.map(|(user, liked_viewer)| Candidate { user, liked_viewer }));
+					
+					if exhausted {
+						break;
+					}
+					
+				}
+				candidates.truncate(limit);
+				
+				let candidates = strategy.rank(&viewer, candidates);
+				
+				Ok::<_, diesel::result::Error>((viewer, candidates))
 				
 			}).await;
 		
-		match result {
-			None => None,
-			Some(candidate_users) =>
-				Some(Self::users_to_profiles(candidate_users))
-		}
+		result.map(|(viewer, candidates)| Self::users_to_profiles(candidates, &viewer))
 		
 	}
 	
-	pub async fn get_match_state(&self, id1: &Id, id2: &Id) -> Option<MatchState> {
+	// None if neither has seen the other yet
+	pub async fn get_match_state(&self, id1: &Id, id2: &Id) -> Result<Option<MatchState>, DbError> {
 		
 		//let (id1, id2) = (id1.clone(), id2.clone());
 		let (id1, id2) = Match::order(id1.clone(), id2.clone());
@@ -340,15 +628,23 @@ impl DatabaseState {
 		use schema::matches::{self, dsl::state};
 		
 		self.execute(
+			"get_match_state",
 			move |connection|
 				matches::table
 					.select(state)
 					.find((&**id1, &**id2))
 					.first::<MatchState>(connection)
+					.optional()
 		).await
 		
 	}
-	pub async fn set_match_state(&self, id1: &Id, id2: &Id, new_state: MatchState) -> Option<()> {
+	// Blocking and unmatching both kill the match, so this also rules those out
+	pub async fn is_active_match(&self, id1: &Id, id2: &Id) -> Result<bool, DbError> {
+		self.get_match_state(id1, id2)
+			.await
+			.map(|state| matches!(state, Some(MatchState::Active)))
+	}
+	pub async fn set_match_state(&self, id1: &Id, id2: &Id, new_state: MatchState) -> Result<(), DbError> {
 		
 		use schema::matches::{self, dsl::*};
 		
@@ -357,6 +653,7 @@ impl DatabaseState {
 		
 		Self::strip(
 			self.execute_expect(
+				"set_match_state",
 				"Error setting match state",
 				move |connection|
 					insert_into(matches::table)
@@ -370,47 +667,403 @@ impl DatabaseState {
 		
 	}
 	
-	pub async fn get_initial_chat_messages(&self, user_id: Id) -> Option<Vec<ChatMessage>> {
+	// Kills an active match and deletes its messages.
+	// Returns whether the two were actually matched.
+	pub async fn unmatch(&self, id1: &Id, id2: &Id) -> Result<bool, DbError> {
 		
-		use schema::{matches, messages};
+		use schema::{matches, messages, read_markers};
+		
+		let (id1, id2) = Match::order(id1.clone(), id2.clone());
 		
-		//let user_id_clone = user_id.clone();
-		//let user_id = user_id.clone();
 		self.execute_expect(
-			"Error getting user recent messages",
-			move |connection| {
+			"unmatch",
+			"Error unmatching users",
+			move |connection| connection.transaction(|connection| {
 				
-				matches::table
+				let unmatched = update(matches::table.find((&**id1, &**id2)))
 					.filter(matches::state.eq(MatchState::Active))
-					.filter(
-						matches::user1.eq(&*user_id)
-							.or(matches::user2.eq(&*user_id)))
-					.inner_join(messages::table.on(
-						matches::user1.eq(messages::user1)
-							.and(matches::user2.eq(messages::user2))))
-					.select(ChatMessage::as_select())
-					.order(messages::timestamp.desc())
-					// naively loads the last 20 messages - this will need to change
-					.limit(20)
-					//.order(messages)
-					//.group()
-					//.filter()
+					.set(matches::state.eq(MatchState::Dead))
+					.execute(connection)? > 0;
+				
+				if unmatched {
+					delete(messages::table
+						.filter(messages::user1.eq(&*id1))
+						.filter(messages::user2.eq(&*id2)))
+						.execute(connection)?;
+					delete(read_markers::table
+						.filter(read_markers::user1.eq(&*id1))
+						.filter(read_markers::user2.eq(&*id2)))
+						.execute(connection)?;
+				}
+				
+				Ok::<_, diesel::result::Error>(unmatched)
+				
+			})
+		).await
+		
+	}
+	
+	// Files a report and blocks the reported user, killing any match between them.
+	// Messages are kept, since the report may reference them.
+	// Returns whether the two were actively matched.
+	pub async fn report(&self, report: Report) -> Result<bool, DbError> {
+		
+		use schema::{reports, blocks, matches};
+		
+		self.execute_expect(
+			"report",
+			"Error filing report",
+			move |connection| connection.transaction(|connection| {
+				
+				insert_into(reports::table)
+					.values(&report)
+					.execute(connection)?;
+				
+				insert_into(blocks::table)
+					.values((
+						blocks::blocker.eq(&report.reporter),
+						blocks::blocked.eq(&report.reported)
+					))
+					.on_conflict_do_nothing()
+					.execute(connection)?;
+				
+				let (id1, id2) = Match::order(&report.reporter, &report.reported);
+				let was_active = matches::table
+					.select(matches::state)
+					.find((id1, id2))
+					.first::<MatchState>(connection)
+					.optional()?
+					.is_some_and(|state| matches!(state, MatchState::Active));
+				
+				insert_into(matches::table)
+					.values((
+						matches::user1.eq(id1),
+						matches::user2.eq(id2),
+						matches::state.eq(MatchState::Dead)
+					))
+					.on_conflict((matches::user1, matches::user2))
+					.do_update()
+					.set(matches::state.eq(MatchState::Dead))
+					.execute(connection)?;
+				
+				Ok::<_, diesel::result::Error>(was_active)
+				
+			})
+		).await
+		
+	}
+	
+	// The latest `per_match` messages of every active match, newest first
+	pub async fn get_initial_chat_messages(&self, user_id: Id, per_match: i64) -> Result<Vec<ChatMessage>, DbError> {
+		
+		use diesel::sql_query;
+		use diesel::sql_types::{Text, Integer, BigInt};
+		
+		// No window function support in the query builder
+		self.execute_expect(
+			"get_initial_chat_messages",
+			"Error getting user recent messages",
+			move |connection|
+				sql_query("
+					SELECT id, user1, user2, sender, timestamp, content FROM (
+						SELECT messages.*, ROW_NUMBER() OVER (
+							PARTITION BY messages.user1, messages.user2
+							ORDER BY messages.seq DESC
+						) AS position
+						FROM messages
+						INNER JOIN matches
+							ON matches.user1 = messages.user1
+							AND matches.user2 = messages.user2
+						WHERE matches.state = ? AND (matches.user1 = ? OR matches.user2 = ?)
+					)
+					WHERE position <= ?
+					ORDER BY seq DESC
+				")
+					.bind::<Integer, _>(MatchState::Active)
+					.bind::<Text, _>(&*user_id)
+					.bind::<Text, _>(&*user_id)
+					.bind::<BigInt, _>(per_match)
 					.load::<ChatMessage>(connection)
+		).await
+		
+	}
+	pub async fn get_read_states(&self, user_id: Id) -> Result<Vec<ReadState>, DbError> {
+		
+		use diesel::sql_query;
+		use diesel::sql_types::{Text, Integer};
+		
+		self.execute_expect(
+			"get_read_states",
+			"Error getting user read states",
+			move |connection|
+				sql_query("
+					SELECT
+						pairs.other AS user,
+						(
+							SELECT COUNT(*) FROM messages
+							WHERE messages.user1 = pairs.user1 AND messages.user2 = pairs.user2
+								AND messages.sender != pairs.me
+								AND (mine.seq IS NULL OR messages.seq > mine.seq)
+						) AS unread_count,
+						theirs.message_id AS seen_up_to
+					FROM (
+						SELECT user1, user2,
+							CASE WHEN user1 = ?1 THEN 0 ELSE 1 END AS me,
+							CASE WHEN user1 = ?1 THEN user2 ELSE user1 END AS other
+						FROM matches
+						WHERE state = ?2 AND (user1 = ?1 OR user2 = ?1)
+					) AS pairs
+					LEFT JOIN read_markers AS mine
+						ON mine.user1 = pairs.user1 AND mine.user2 = pairs.user2
+						AND mine.reader = pairs.me
+					LEFT JOIN read_markers AS theirs
+						ON theirs.user1 = pairs.user1 AND theirs.user2 = pairs.user2
+						AND theirs.reader != pairs.me
+				")
+					.bind::<Text, _>(&*user_id)
+					.bind::<Integer, _>(MatchState::Active)
+					.load::<ReadState>(connection)
+		).await
+		
+	}
+	// Moves the reader's marker forward to `up_to` (never backwards).
+	// Returns whether it moved, or NotFound if `up_to` isn't in the conversation.
+	pub async fn mark_read(&self, reader_id: &Id, other_id: &Id, up_to: String) -> Result<bool, DbError> {
+		
+		use schema::{messages, read_markers};
+		
+		let reader = Sender::of(reader_id, other_id);
+		let (id1, id2) = Match::order(reader_id.clone(), other_id.clone());
+		
+		self.execute_expect(
+			"mark_read",
+			"Error marking messages read",
+			move |connection| connection.transaction(|connection| {
 				
+				let (message, seq) = messages::table
+					.select((ChatMessage::as_select(), messages::seq))
+					.filter(messages::user1.eq(&*id1))
+					.filter(messages::user2.eq(&*id2))
+					.find(&up_to)
+					.first::<(ChatMessage, i32)>(connection)?;
 				
-			}
-		).await	
+				let current = read_markers::table
+					.select(read_markers::seq)
+					.find((&*id1, &*id2, &reader))
+					.first::<i32>(connection)
+					.optional()?;
+				
+				if current.is_some_and(|current| current >= seq) {
+					return Ok(false);
+				}
+				
+				insert_into(read_markers::table)
+					.values((
+						read_markers::user1.eq(&*id1),
+						read_markers::user2.eq(&*id2),
+						read_markers::reader.eq(&reader),
+						read_markers::message_id.eq(&message.id),
+						read_markers::timestamp.eq(&message.timestamp),
+						read_markers::seq.eq(seq)
+					))
+					.on_conflict((read_markers::user1, read_markers::user2, read_markers::reader))
+					.do_update()
+					.set((
+						read_markers::message_id.eq(&message.id),
+						read_markers::timestamp.eq(&message.timestamp),
+						read_markers::seq.eq(seq)
+					))
+					.execute(connection)?;
+				
+				Ok::<_, diesel::result::Error>(true)
+				
+			})
+		).await
+		
+	}
+	
+	pub async fn touch_last_seen(&self, user_id: &Id) -> Result<(), DbError> {
+		
+		use schema::users::{self, dsl::*};
+		use diesel::dsl::sql;
+		use diesel::sql_types::Text;
+		
+		let user_id = user_id.clone();
+		
+		Self::strip(
+			self.execute_expect(
+				"touch_last_seen",
+				"Error updating last seen",
+				move |connection|
+					update(users::table.find(&*user_id))
+						.set(last_seen.eq(sql::<Nullable<Text>>("CURRENT_TIMESTAMP")))
+						.execute(connection)
+			).await
+		)
+		
+	}
+	pub async fn get_active_match_ids(&self, user_id: &Id) -> Result<Vec<Id>, DbError> {
+		
+		use schema::matches::{self, dsl::*};
+		
+		let user_id = user_id.clone();
+		
+		let result = self.execute_expect(
+			"get_active_match_ids",
+			"Error getting active match ids",
+			move |connection|
+				matches::table
+					.select(user2)
+					.filter(user1.eq(&*user_id))
+					.filter(state.eq(MatchState::Active))
+					.union(
+						matches::table
+							.select(user1)
+							.filter(user2.eq(&*user_id))
+							.filter(state.eq(MatchState::Active)))
+					.load::<String>(connection)
+		).await;
+		
+		result.map(|ids| ids.into_iter().map(Id::new).collect())
+		
+	}
+	
+	pub async fn put_device_token(&self, user_id: &Id, token: String) -> Result<(), DbError> {
+		
+		use schema::device_tokens::{self, dsl};
+		
+		let user_id = user_id.clone();
+		
+		Self::strip(
+			self.execute_expect(
+				"put_device_token",
+				"Error registering device token",
+				move |connection|
+					insert_into(device_tokens::table)
+						.values((dsl::token.eq(&token), dsl::user_id.eq(&*user_id)))
+						.on_conflict(dsl::token)
+						.do_update()
+						.set(dsl::user_id.eq(&*user_id))
+						.execute(connection)
+			).await
+		)
+		
+	}
+	// Tokens are only removable by their owner, or by us once FCM rejects them
+	pub async fn delete_device_token(&self, user_id: Option<&Id>, token: String) -> Result<(), DbError> {
+		
+		use schema::device_tokens::{self, dsl};
+		
+		let user_id = user_id.cloned();
+		
+		Self::strip(
+			self.execute_expect(
+				"delete_device_token",
+				"Error deleting device token",
+				move |connection| {
+					let mut query = delete(device_tokens::table)
+						.filter(dsl::token.eq(&token))
+						.into_boxed();
+					if let Some(user_id) = user_id {
+						query = query.filter(dsl::user_id.eq(user_id.to_string()));
+					}
+					query.execute(connection)
+				}
+			).await
+		)
+		
+	}
+	pub async fn get_device_tokens(&self, user_id: &Id) -> Result<Vec<String>, DbError> {
+		
+		use schema::device_tokens::{self, dsl};
+		
+		let user_id = user_id.clone();
+		
+		self.execute_expect(
+			"get_device_tokens",
+			"Error getting device tokens",
+			move |connection|
+				device_tokens::table
+					.select(dsl::token)
+					.filter(dsl::user_id.eq(&*user_id))
+					.load::<String>(connection)
+		).await
+		
+	}
+	
+	// Returns the event's seq
+	pub async fn put_outbox(&self, user_id: &Id, payload: String) -> Result<i32, DbError> {
+		
+		use schema::outbox::{self, dsl};
+		
+		let user_id = user_id.clone();
+		
+		self.execute_expect(
+			"put_outbox",
+			"Error queueing event",
+			move |connection|
+				insert_into(outbox::table)
+					.values((dsl::user_id.eq(&*user_id), dsl::payload.eq(&payload)))
+					.returning(dsl::seq)
+					.get_result::<i32>(connection)
+		).await
+		
+	}
+	// Pending events in delivery order, as (seq, payload)
+	pub async fn get_outbox(&self, user_id: &Id) -> Result<Vec<(i32, String)>, DbError> {
+		
+		use schema::outbox::{self, dsl};
+		
+		let user_id = user_id.clone();
+		
+		self.execute_expect(
+			"get_outbox",
+			"Error getting undelivered events",
+			move |connection|
+				outbox::table
+					.select((dsl::seq, dsl::payload))
+					.filter(dsl::user_id.eq(&*user_id))
+					.order(dsl::seq.asc())
+					.load::<(i32, String)>(connection)
+		).await
+		
+	}
+	// Drops everything up to and including `seq`, which the client has confirmed
+	pub async fn ack_outbox(&self, user_id: &Id, seq: i32) -> Result<(), DbError> {
+		
+		use schema::outbox::{self, dsl};
+		
+		let user_id = user_id.clone();
+		
+		Self::strip(
+			self.execute_expect(
+				"ack_outbox",
+				"Error acknowledging events",
+				move |connection|
+					delete(outbox::table
+						.filter(dsl::user_id.eq(&*user_id))
+						.filter(dsl::seq.le(seq)))
+						.execute(connection)
+			).await
+		)
 		
 	}
-	pub async fn get_initial_match_profiles(&self, user_id: Id) -> Option<Vec<Profile>> {
+	
+	pub async fn get_initial_match_profiles(&self, user_id: Id) -> Result<Vec<Profile>, DbError> {
 		
 		//use schema::users::{self, dsl::*};
 		use schema::{users, matches};
 		
 		let result = self.execute_expect(
+			"get_initial_match_profiles",
 			"Error getting user matches",
 			move |connection| {
 				
+				let viewer = users::table
+					.select(User::as_select())
+					.find(&*user_id)
+					.first::<User>(connection)?;
+				
 				let users1 = matches::table
 					.filter(matches::dsl::user1.eq(&*user_id))
 					.filter(matches::state.eq(MatchState::Active))
@@ -423,57 +1076,307 @@ impl DatabaseState {
 					.inner_join(users::table.on(users::id.eq(matches::user1)))
 					.select(User::as_select());
 				
-				users1.union(users2).load::<User>(connection)
+				let matched = users1.union(users2).load::<User>(connection)?;
 				
-			}
+				Ok::<_, diesel::result::Error>((viewer, matched))
 				
+			}
+			
 		).await;
 		
-		result.map(|users| Self::users_to_profiles(users))
+		result.map(|(viewer, matched)| Self::users_to_profiles(matched, &viewer))
 		
 	}
-	pub async fn put_chat_message(&self, sender_id: Id, receiver_id: Id, id: String, content: String) -> Option<()> {
+	// Returns the stored message, with its database-assigned timestamp
+	pub async fn put_chat_message(&self, sender_id: Id, receiver_id: Id, id: String, content: String) -> Result<ChatMessage, DbError> {
 		
 		use schema::messages::{self, dsl};
 		
 		let sender = Sender::of(&sender_id, &receiver_id);
 		let (id1, id2) = Match::order(sender_id, receiver_id);
 		
-		Self::strip(
-			self.execute_expect(
-				"Error inserting chat message",
-				move |connection|
-					insert_into(messages::table)
-						.values((
-							dsl::id.eq(&id),
-							dsl::user1.eq(&**id1),
-							dsl::user2.eq(&**id2),
-							dsl::sender.eq(&sender),
-							dsl::content.eq(&content)
-						))
-						.execute(connection)
-			).await
-		)
+		self.execute_expect(
+			"put_chat_message",
+			"Error inserting chat message",
+			move |connection|
+				insert_into(messages::table)
+					.values((
+						dsl::id.eq(&id),
+						dsl::user1.eq(&**id1),
+						dsl::user2.eq(&**id2),
+						dsl::sender.eq(&sender),
+						dsl::content.eq(&content)
+					))
+					.returning(ChatMessage::as_returning())
+					.get_result::<ChatMessage>(connection)
+		).await
 		
 	}
-	pub async fn get_chat_messages(&self, user1: Id, user2: Id, limit: i64, older_than: String) -> Option<Vec<ChatMessage>> {
+	// Newest-first page of a conversation, strictly older than the message `before`.
+	// Ordered by seq, since timestamps only have second resolution.
+	pub async fn get_chat_messages(&self, user1: Id, user2: Id, limit: i64, before: Option<String>) -> Result<Vec<ChatMessage>, DbError> {
 		
 		use schema::messages::{self, dsl};
 		
 		let (id1, id2) = Match::order(user1, user2);
 		
 		self.execute_expect(
+			"get_chat_messages",
 			"Error getting chat history",
-			move |connection|
-				messages::table
+			move |connection| {
+				
+				let mut query = messages::table
 					.select(ChatMessage::as_select())
 					.filter(dsl::user1.eq(&*id1))
 					.filter(dsl::user2.eq(&*id2))
-					.filter(dsl::timestamp.lt(older_than))
+					.into_boxed();
+				
+				if let Some(before) = before {
+					
+					let cursor = messages::table
+						.select(dsl::seq)
+						.filter(dsl::user1.eq(&*id1))
+						.filter(dsl::user2.eq(&*id2))
+						.find(&before)
+						.first::<i32>(connection)?;
+					
+					query = query.filter(dsl::seq.lt(cursor));
+					
+				}
+				
+				query
+					.order(dsl::seq.desc())
 					.limit(limit)
 					.load::<ChatMessage>(connection)
+				
+			}
 		).await
 		
 	}
 	
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	
+	use super::*;
+	
+	#[tokio::test]
+	async fn preferences_can_be_cleared() {
+		
+		let db = DatabaseState::temporary();
+		let id = Id::new("alice".to_string());
+		db.read_user(&id).await.unwrap();
+		
+		let mut user = User::new(id.clone());
+		user.age_min = Some(25);
+		user.max_distance = Some(50);
+		db.write_user(user).await.unwrap();
+		
+		// A plain write leaves preferences alone, even when they're missing
+		db.write_user(User { name: Some("Alice".to_string()), ..User::new(id.clone()) }).await.unwrap();
+		assert_eq!(db.get_user(&id).await.unwrap().age_min, Some(25));
+		
+		db.write_preferences(&id, Preferences { max_distance: Some(10), ..Default::default() }).await.unwrap();
+		let user = db.get_user(&id).await.unwrap();
+		assert_eq!(user.age_min, None);
+		assert_eq!(user.max_distance, Some(10));
+		
+		let missing = Id::new("nobody".to_string());
+		assert!(matches!(db.write_preferences(&missing, Preferences::default()).await, Err(DbError::NotFound)));
+		
+	}
+	
+	#[tokio::test]
+	async fn reports_end_active_matches() {
+		
+		let db = DatabaseState::temporary();
+		let (alice, bob) = (Id::new("alice".to_string()), Id::new("bob".to_string()));
+		db.read_user(&alice).await.unwrap();
+		db.read_user(&bob).await.unwrap();
+		
+		assert!(!db.is_active_match(&alice, &bob).await.unwrap());
+		
+		db.set_match_state(&alice, &bob, MatchState::Active).await.unwrap();
+		assert!(db.is_active_match(&bob, &alice).await.unwrap());
+		
+		db.report(Report::new(&bob, &alice, "spam".to_string(), None, None)).await.unwrap();
+		assert!(!db.is_active_match(&alice, &bob).await.unwrap());
+		
+	}
+	
+	#[tokio::test]
+	async fn queue_candidates_are_capped_with_likes_first() {
+		
+		use schema::users::{self, dsl};
+		
+		let db = DatabaseState::temporary();
+		let viewer = Id::new("viewer".to_string());
+		db.read_user(&viewer).await.unwrap();
+		
+		let limit = db.queue_strategy.candidate_limit();
+		let others: Vec<String> = (0..limit + 10).map(|index| format!("user{index:04}")).collect();
+		let rows: Vec<_> = others.iter().map(|other| dsl::id.eq(other.clone())).collect();
+		db.execute("insert_test_users", move |connection| insert_into(users::table).values(&rows).execute(connection)).await.unwrap();
+		
+		// Past the cap if loaded in id order
+		let admirer = Id::new(others.last().unwrap().clone());
+		db.set_match_state(&viewer, &admirer, MatchState::Pending(Sender::of(&admirer, &viewer))).await.unwrap();
+		
+		let profiles = db.get_queue_profiles(&viewer, None).await.unwrap();
+		
+		assert_eq!(profiles.len(), db.queue_strategy.page_size());
+		assert_eq!(profiles[0].id, *admirer);
+		
+	}
+	
+	#[tokio::test]
+	async fn queue_looks_past_candidates_the_viewer_would_reject() {
+		
+		use schema::users::{self, dsl};
+		
+		let db = DatabaseState::temporary();
+		let viewer = Id::new("viewer".to_string());
+		db.read_user(&viewer).await.unwrap();
+		db.write_preferences(&viewer, Preferences { seeking_genders: Some("woman".to_string()), ..Default::default() }).await.unwrap();
+		
+		// A full pool of more recently active candidates, none of whom fit
+		let limit = db.queue_strategy.candidate_limit();
+		let rows: Vec<_> = (0..limit + 10)
+			.map(|index| (
+				dsl::id.eq(format!("man{index:04}")),
+				dsl::gender_identity.eq("man"),
+				dsl::last_seen.eq("2024-06-02 00:00:00")
+			))
+			.collect();
+		db.execute("insert_test_users", move |connection| insert_into(users::table).values(&rows).execute(connection)).await.unwrap();
+		db.execute("insert_test_users", move |connection|
+			insert_into(users::table)
+				.values((dsl::id.eq("woman"), dsl::gender_identity.eq("woman"), dsl::last_seen.eq("2024-06-01 00:00:00")))
+				.execute(connection)
+		).await.unwrap();
+		
+		let profiles = db.get_queue_profiles(&viewer, None).await.unwrap();
+		
+		assert_eq!(profiles.len(), 1);
+		assert_eq!(profiles[0].id, "woman");
+		
+	}
+	
+	#[tokio::test]
+	async fn queue_prefilters_timestamped_birth_dates_by_date() {
+		
+		use schema::users::{self, dsl};
+		
+		let db = DatabaseState::temporary();
+		let viewer = Id::new("viewer".to_string());
+		db.read_user(&viewer).await.unwrap();
+		db.write_preferences(&viewer, Preferences { age_min: Some(18), ..Default::default() }).await.unwrap();
+		
+		// Turns 18 today
+		let (_, born_before) = db.get_user(&viewer).await.unwrap().birth_date_range();
+		let birth_date = format!("{}T12:00:00Z", born_before.unwrap());
+		db.execute("insert_test_users", move |connection|
+			insert_into(users::table)
+				.values((dsl::id.eq("adult"), dsl::birth_date.eq(birth_date)))
+				.execute(connection)
+		).await.unwrap();
+		
+		let profiles = db.get_queue_profiles(&viewer, None).await.unwrap();
+		
+		assert_eq!(profiles.len(), 1);
+		assert_eq!(profiles[0].age, Some(18));
+		
+	}
+	
+	#[tokio::test]
+	async fn message_seq_never_goes_backwards() {
+		
+		use schema::messages::{self, dsl};
+		
+		let db = DatabaseState::temporary();
+		let (alice, bob) = (Id::new("alice".to_string()), Id::new("bob".to_string()));
+		db.read_user(&alice).await.unwrap();
+		db.read_user(&bob).await.unwrap();
+		db.set_match_state(&alice, &bob, MatchState::Active).await.unwrap();
+		
+		for id in ["m1", "m2"] {
+			db.put_chat_message(alice.clone(), bob.clone(), id.to_string(), id.to_string()).await.unwrap();
+		}
+		
+		// Frees up every rowid handed out so far
+		db.unmatch(&alice, &bob).await.unwrap();
+		db.set_match_state(&alice, &bob, MatchState::Active).await.unwrap();
+		db.put_chat_message(alice.clone(), bob.clone(), "m3".to_string(), "m3".to_string()).await.unwrap();
+		
+		let seq = db.execute("get_test_seq", move |connection|
+			messages::table.select(dsl::seq).find("m3").first::<i32>(connection)
+		).await.unwrap();
+		assert_eq!(seq, 3);
+		
+	}
+	
+	#[tokio::test]
+	async fn messages_keep_insertion_order_within_a_second() {
+		
+		let db = DatabaseState::temporary();
+		let (alice, bob) = (Id::new("alice".to_string()), Id::new("bob".to_string()));
+		db.read_user(&alice).await.unwrap();
+		db.read_user(&bob).await.unwrap();
+		db.set_match_state(&alice, &bob, MatchState::Active).await.unwrap();
+		
+		// Ids sort the opposite way to when they were sent
+		for id in ["m3", "m2", "m1"] {
+			db.put_chat_message(alice.clone(), bob.clone(), id.to_string(), id.to_string()).await.unwrap();
+		}
+		
+		let ids = |messages: Vec<ChatMessage>| messages.into_iter().map(|message| message.id).collect::<Vec<_>>();
+		
+		let history = db.get_chat_messages(bob.clone(), alice.clone(), 10, None).await.unwrap();
+		assert_eq!(ids(history), vec!["m1", "m2", "m3"]);
+		let older = db.get_chat_messages(bob.clone(), alice.clone(), 10, Some("m2".to_string())).await.unwrap();
+		assert_eq!(ids(older), vec!["m3"]);
+		
+		let recent = db.get_initial_chat_messages(bob.clone(), 2).await.unwrap();
+		assert_eq!(ids(recent), vec!["m1", "m2"]);
+		
+		// Only m1 was sent after m2
+		assert!(db.mark_read(&bob, &alice, "m2".to_string()).await.unwrap());
+		let read_states = db.get_read_states(bob.clone()).await.unwrap();
+		assert_eq!(read_states[0].unread_count, 1);
+		
+		// Never backwards
+		assert!(!db.mark_read(&bob, &alice, "m3".to_string()).await.unwrap());
+		assert!(matches!(db.mark_read(&bob, &alice, "m4".to_string()).await, Err(DbError::NotFound)));
+		assert!(db.mark_read(&bob, &alice, "m1".to_string()).await.unwrap());
+		let read_states = db.get_read_states(bob.clone()).await.unwrap();
+		assert_eq!(read_states[0].unread_count, 0);
+		
+	}
+	
+	#[tokio::test]
+	async fn backfill_geohashes_fills_in_located_users() {
+		
+		use schema::users::{self, dsl};
+		
+		let db = DatabaseState::temporary();
+		
+		db.execute("insert_test_users", |connection| insert_into(users::table)
+			.values(&vec![
+				(dsl::id.eq("located"), dsl::latitude.eq(Some(57.64911)), dsl::longitude.eq(Some(10.40744))),
+				(dsl::id.eq("nowhere"), dsl::latitude.eq(None), dsl::longitude.eq(None))
+			])
+			.execute(connection)
+		).await.unwrap();
+		
+		assert_eq!(db.backfill_geohashes().await.unwrap(), 1);
+		assert_eq!(db.backfill_geohashes().await.unwrap(), 0);
+		
+		let located = db.get_user(&Id::new("located".to_string())).await.unwrap();
+		let nowhere = db.get_user(&Id::new("nowhere".to_string())).await.unwrap();
+		assert_eq!(located.geohash.as_deref(), Some("u4pru"));
+		assert_eq!(nowhere.geohash, None);
+		
+	}
+	
+}
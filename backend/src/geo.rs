@@ -0,0 +1,165 @@
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+const BASE32: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+// Precision stored on users; cells are ~4.9km square
+pub const GEOHASH_PRECISION: usize = 5;
+
+// Approximate cell (height, width at the equator) in km, indexed by precision
+const CELL_SIZES_KM: [(f64, f64); GEOHASH_PRECISION + 1] = [
+	(20000.0, 40000.0),
+	(5000.0, 5000.0),
+	(625.0, 1250.0),
+	(156.0, 156.0),
+	(19.5, 39.1),
+	(4.89, 4.89)
+];
+
+
+pub fn distance_km(lat1: f32, long1: f32, lat2: f32, long2: f32) -> f64 {
+	
+	// Haversine distance; f32 is too lossy at these scales, so widen first
+	let (lat1, lat2) = ((lat1 as f64).to_radians(), (lat2 as f64).to_radians());
+	let dlat = lat2 - lat1;
+	let dlong = (long2 as f64 - long1 as f64).to_radians();
+	
+	let a = (dlat / 2.0).sin().powi(2)
+		+ lat1.cos() * lat2.cos() * (dlong / 2.0).sin().powi(2);
+	
+	2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+	
+}
+
+pub fn encode(latitude: f64, longitude: f64, precision: usize) -> String {
+	
+	let (mut lat_range, mut long_range) = ((-90.0, 90.0), (-180.0, 180.0));
+	let mut hash = String::with_capacity(precision);
+	
+	// Bits alternate longitude/latitude, starting with longitude
+	let mut even = true;
+	let (mut bits, mut index) = (0, 0usize);
+	
+	while hash.len() < precision {
+		
+		let (range, value): (&mut (f64, f64), f64) = if even {
+			(&mut long_range, longitude)
+		} else {
+			(&mut lat_range, latitude)
+		};
+		
+		let mid = (range.0 + range.1) / 2.0;
+		index <<= 1;
+		if value >= mid {
+			index |= 1;
+			range.0 = mid;
+		} else {
+			range.1 = mid;
+		}
+		
+		even = !even;
+		bits += 1;
+		
+		if bits == 5 {
+			hash.push(BASE32[index] as char);
+			(bits, index) = (0, 0);
+		}
+		
+	}
+	
+	hash
+	
+}
+
+// The cell containing the point at the finest precision whose cells still span
+// `radius_km` in every direction, plus its 8 neighbours. Any point within
+// `radius_km` is guaranteed to fall inside one of them.
+pub fn neighbourhood(latitude: f32, longitude: f32, radius_km: f64) -> Option<Vec<String>> {
+	
+	let (latitude, longitude) = (latitude as f64, longitude as f64);
+	let shrink = latitude.to_radians().cos();
+	
+	let precision = (1..=GEOHASH_PRECISION)
+		.rev()
+		.find(|&precision| {
+			let (height, width) = CELL_SIZES_KM[precision];
+			height.min(width * shrink) >= radius_km
+		})?;
+	
+	// Cell size in degrees at this precision
+	let long_bits = (5 * precision).div_ceil(2);
+	let lat_bits = 5 * precision / 2;
+	let dlat = 180.0 / (1u64 << lat_bits) as f64;
+	let dlong = 360.0 / (1u64 << long_bits) as f64;
+	
+	let mut cells: Vec<String> = [-1.0, 0.0, 1.0]
+		.iter()
+		.flat_map(|&y| [-1.0, 0.0, 1.0].map(move |x| (y, x)))
+		.map(|(y, x): (f64, f64)| {
+			let lat = (latitude + y * dlat).clamp(-90.0, 90.0 - f64::EPSILON);
+			let long = (longitude + x * dlong + 540.0).rem_euclid(360.0) - 180.0;
+			encode(lat, long, precision)
+		})
+		.collect();
+	
+	cells.sort();
+	cells.dedup();
+	
+	Some(cells)
+	
+}
+
+
+#[cfg(test)]
+mod tests {
+	
+	use super::*;
+	
+	#[test]
+	fn encode_matches_reference_hashes() {
+		assert_eq!(encode(57.64911, 10.40744, 11), "u4pruydqqvj");
+		assert_eq!(encode(57.64911, 10.40744, GEOHASH_PRECISION), "u4pru");
+		assert_eq!(encode(-33.8688, 151.2093, 5), "r3gx2");
+		assert_eq!(encode(0.0, 0.0, 1), "s");
+	}
+	
+	#[test]
+	fn distance_between_cities() {
+		// London to Paris is about 344km
+		let distance = distance_km(51.5074, -0.1278, 48.8566, 2.3522);
+		assert!((distance - 343.5).abs() < 1.0, "{distance}");
+		assert_eq!(distance_km(10.0, 10.0, 10.0, 10.0), 0.0);
+	}
+	
+	#[test]
+	fn neighbourhood_uses_finest_covering_precision() {
+		
+		// 4.9km cells are too small for 10km, 19.5km ones aren't
+		let cells = neighbourhood(0.0, 0.0, 10.0).unwrap();
+		assert_eq!(cells.len(), 9);
+		assert!(cells.iter().all(|cell| cell.len() == 4));
+		
+		assert_eq!(neighbourhood(0.0, 0.0, 2.0).unwrap()[0].len(), GEOHASH_PRECISION);
+		
+		// Wider than any cell
+		assert!(neighbourhood(0.0, 0.0, 10000.0).is_none());
+		
+	}
+	
+	#[test]
+	fn neighbourhood_covers_the_radius() {
+		
+		let (latitude, longitude) = (51.5074, -0.1278);
+		let cells = neighbourhood(latitude, longitude, 10.0).unwrap();
+		let precision = cells[0].len();
+		
+		// Roughly 9.5km away in each direction
+		for (dlat, dlong) in [(0.085, 0.0), (-0.085, 0.0), (0.0, 0.137), (0.0, -0.137), (0.06, 0.097)] {
+			let (lat, long) = (latitude + dlat, longitude + dlong);
+			assert!(distance_km(latitude, longitude, lat, long) < 10.0);
+			let cell = encode(lat as f64, long as f64, precision);
+			assert!(cells.contains(&cell), "{cell} not in {cells:?}");
+		}
+		
+	}
+	
+}
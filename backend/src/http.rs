@@ -1,14 +1,17 @@
 
 
 use crate::Id;
+use crate::db::DbError;
 use crate::models::{
 	
 	Profile,
 	ChatMessage,
+	ReadState,
 	
 	Sender
 };
 use serde::{Serialize, Deserialize};
+use axum::http::StatusCode;
 
 
 
@@ -16,21 +19,88 @@ use serde::{Serialize, Deserialize};
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct InitialMatchData {
 	profiles: Vec<Profile>,
-	messages: Vec<RemoteChatMessage>
+	messages: Vec<RemoteChatMessage>,
+	read_states: Vec<ReadState>
 }
 impl InitialMatchData {
 	
-	pub fn new(profiles: Vec<Profile>, messages: Vec<ChatMessage>, for_user: &Id) -> Self {
+	pub const MESSAGES_PER_MATCH: i64 = 20;
+	
+	pub fn new(profiles: Vec<Profile>, messages: Vec<ChatMessage>, read_states: Vec<ReadState>, for_user: &Id) -> Self {
 		Self {
 			profiles,
-			messages: RemoteChatMessage::new_vec(messages, for_user)
+			messages: RemoteChatMessage::new_vec(messages, for_user),
+			read_states
+		}
+	}
+	
+}
+
+
+#[derive(Debug)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatHistoryPage {
+	// Oldest first
+	messages: Vec<RemoteChatMessage>,
+	// Pass back as `before` for the next (older) page; absent once there's nothing older
+	#[serde(skip_serializing_if = "Option::is_none")]
+	cursor: Option<String>
+}
+impl ChatHistoryPage {
+	
+	pub const DEFAULT_LIMIT: i64 = 50;
+	pub const MAX_LIMIT: i64 = 100;
+	
+	pub fn clamp_limit(limit: Option<i64>) -> i64 {
+		limit.unwrap_or(Self::DEFAULT_LIMIT).clamp(1, Self::MAX_LIMIT)
+	}
+	
+	// `messages` as returned by DatabaseState::get_chat_messages, newest first
+	pub fn new(mut messages: Vec<ChatMessage>, limit: i64, for_user: &Id) -> Self {
+		
+		let cursor = if messages.len() as i64 >= limit {
+			messages.last().map(|message| message.id.clone())
+		} else {
+			None
+		};
+		
+		messages.reverse();
+		
+		Self {
+			messages: RemoteChatMessage::new_vec(messages, for_user),
+			cursor
 		}
+		
 	}
 	
 }
 
+#[derive(Deserialize)]
+pub struct ChatHistoryQuery {
+	pub before: Option<String>,
+	pub limit: Option<i64>
+}
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct DeviceTokenRequest {
+	pub token: String
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ReportRequest {
+	pub to_id: String,
+	pub reason: String,
+	pub details: Option<String>,
+	pub message_ids: Option<Vec<String>>
+}
+
 
 //#[derive(Selectable)]
+#[derive(Debug)]
 #[derive(Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct RemoteChatMessage {
@@ -60,7 +130,7 @@ impl RemoteChatMessage {
 			other_user = message.user1;
 			outgoing = message.sender == Sender::Two;
 		} else {
-			println!("Invalid for_user for RemoteChatMessage");
+			tracing::warn!("Invalid for_user for RemoteChatMessage");
 			other_user = message.user1;
 			outgoing = false;
 		}
@@ -86,6 +156,13 @@ impl RemoteChatMessage {
 }
 
 
-
-
-
+impl From<DbError> for StatusCode {
+	fn from(err: DbError) -> Self {
+		match err {
+			DbError::NotFound => StatusCode::NOT_FOUND,
+			DbError::ConstraintViolation(_) => StatusCode::CONFLICT,
+			DbError::PoolExhausted => StatusCode::SERVICE_UNAVAILABLE,
+			DbError::Serialization(_) | DbError::Query(_) => StatusCode::INTERNAL_SERVER_ERROR
+		}
+	}
+}
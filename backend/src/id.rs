@@ -3,3 +3,21 @@
 use internment::ArcIntern;
 pub type Id = ArcIntern<String>;
 
+use std::fmt;
+use std::hash::{Hash, Hasher, DefaultHasher};
+
+// Logs show a short stable hash instead of the real id, so one user's
+// activity can still be followed without exposing who they are
+pub struct Redacted<'a>(&'a str);
+
+impl fmt::Display for Redacted<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let mut hasher = DefaultHasher::new();
+		self.0.hash(&mut hasher);
+		write!(f, "{:08x}", hasher.finish() as u32)
+	}
+}
+
+pub fn redact(id: &str) -> Redacted<'_> {
+	Redacted(id)
+}
@@ -7,22 +7,34 @@ pub mod db;
 pub mod ws;
 pub mod id;
 pub mod http;
+pub mod queue;
+pub mod geo;
+pub mod push;
+pub mod protocol;
+pub mod metrics;
 pub use id::Id;
+use id::redact;
 
 use models::*;
-use db::DatabaseState;
+use db::{DatabaseState, DbError};
 
-use http::InitialMatchData;
+use http::{InitialMatchData, ChatHistoryPage, ChatHistoryQuery, ReportRequest, DeviceTokenRequest};
+use push::Notifier;
+use protocol::Protocol;
+use metrics::MetricsState;
 use ws::{
 	WebSocket,
 	WebSocketState,
 	WebSocketUpgrade,
+	WebSocketQuery,
 	IncomingMessage,
-	OutgoingMessage
+	OutgoingMessage,
+	ChatFailure,
+	ErrorCode,
+	Reply
 };
 
 
-// look into tokio tracing
 //use std::sync::Arc;
 
 //use db::models::*;
@@ -30,8 +42,12 @@ use ws::{
 //use axum::Error;
 use std::sync::Arc;
 use axum::http::StatusCode;
-use axum::response::Response; // for websocket upgrade
-use axum::extract::{Request, State, FromRef, Json};
+use axum::response::{Response, IntoResponse}; // for websocket upgrade
+use axum::extract::{Request, State, FromRef, Json, Path, Query, MatchedPath};
+
+use tower_http::trace::TraceLayer;
+use tracing::{info, warn, error, instrument, info_span};
+use tracing_subscriber::EnvFilter;
 
 use firebase_auth::{
 	FirebaseAuth,
@@ -53,27 +69,37 @@ use axum::{
 
 
 
+const FIREBASE_PROJECT_ID: &str = "nemesis-finder";
+
 #[derive(Clone)]
 struct AppState {
 	db: DatabaseState,
 	auth: FirebaseAuthState,
-	ws: WebSocketState
+	ws: WebSocketState,
+	metrics: MetricsState
 }
 
 impl AppState {
 	
 	async fn new() -> Self {
 		
-		let db = DatabaseState::new();
-		let ws = WebSocketState::new();
+		let metrics = MetricsState::new();
+		let db = DatabaseState::new(metrics.clone());
+		match db.backfill_geohashes().await {
+			Ok(0) => {},
+			Ok(count) => info!(count, "Backfilled geohashes"),
+			Err(err) => error!(%err, "Error backfilling geohashes")
+		}
+		let notifier = Notifier::from_env(db.clone(), FIREBASE_PROJECT_ID).await;
+		let ws = WebSocketState::new(db.clone(), notifier, metrics.clone());
 		
 		let auth = FirebaseAuthState {
 			firebase_auth: Arc::new(
-				FirebaseAuth::new("nemesis-finder").await
+				FirebaseAuth::new(FIREBASE_PROJECT_ID).await
 			)
 		};
 		
-		Self { db, ws, auth }
+		Self { db, ws, auth, metrics }
 		
 	}
 	
@@ -93,6 +119,11 @@ impl FromRef<AppState> for WebSocketState {
 		app_state.ws.clone()
 	}
 }
+impl FromRef<AppState> for MetricsState {
+	fn from_ref(app_state: &AppState) -> MetricsState {
+		app_state.metrics.clone()
+	}
+}
 
 
 /*
@@ -106,6 +137,12 @@ impl FromRef<AppState> for ClientsState {
 #[tokio::main]
 async fn main() {
 	
+	// JSON lines; RUST_LOG overrides the level, e.g. RUST_LOG=backend=debug for query timings
+	tracing_subscriber::fmt()
+		.json()
+		.with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+		.init();
+	
 	let state = AppState::new().await;
 	
 	use axum::Router;
@@ -113,24 +150,65 @@ async fn main() {
 	
 	let self_router = Router::new()
 		.route("/read", get(read_user))
-		.route("/write", post(write_user));
+		.route("/write", post(write_user))
+		.route("/preferences", post(write_preferences))
+		.route("/device", post(register_device).delete(unregister_device));
 	
 	let router = Router::new()
 		.nest("/self", self_router)
 		.route("/ws", get(ws_upgrade))
 		//.route("/discover", get(get_discover))
 		.route("/matches", get(get_match_data))
+		.route("/matches/:id/messages", get(get_chat_history))
+		.route("/matches/:id/unmatch", post(unmatch_user))
+		.route("/report", post(report_user))
+		.route("/metrics", get(get_metrics))
+		.route("/healthz", get(healthz))
+		.route("/readyz", get(readyz))
 		.fallback(not_found)
+		.layer(TraceLayer::new_for_http().make_span_with(|request: &Request| {
+			// The route template rather than the URI, which can have user ids in it
+			let route = request
+				.extensions()
+				.get::<MatchedPath>()
+				.map(|path| path.as_str().to_string())
+				.unwrap_or_default();
+			info_span!("http", method = %request.method(), route)
+		}))
 		.with_state(state);
 	
 	let listener = tokio::net::TcpListener::bind("0.0.0.0:5050").await.unwrap();
-	println!("Listening!");
+	info!("Listening");
 	axum::serve(listener, router).await.expect("Axum server error");
 	
 }
 
-async fn not_found(request: Request) {
-	println!("Invalid endpoint: {}", request.uri());
+async fn get_metrics(State(db): State<DatabaseState>, State(ws): State<WebSocketState>, State(metrics): State<MetricsState>)
+	-> impl IntoResponse {
+	
+	db.record_pool_status();
+	ws.record_connections();
+	
+	let (content_type, body) = metrics.encode();
+	([(axum::http::header::CONTENT_TYPE, content_type)], body)
+	
+}
+// Up as soon as it's serving at all
+async fn healthz() -> &'static str {
+	"ok"
+}
+// Whether this instance can take traffic. The Firebase key set doesn't need
+// checking here: FirebaseAuth::new panics rather than return without one, this
+// isn't listening until it has, and failed refreshes keep the last set.
+async fn readyz(State(db): State<DatabaseState>) -> (StatusCode, &'static str) {
+	match db.ping().await {
+		Ok(_) => (StatusCode::OK, "ready"),
+		Err(_) => (StatusCode::SERVICE_UNAVAILABLE, "database unavailable")
+	}
+}
+async fn not_found(request: Request) -> StatusCode {
+	warn!(path = request.uri().path(), "Invalid endpoint");
+	StatusCode::NOT_FOUND
 }
 /*async fn get_discover(State(db): State<DatabaseState>, auth: FirebaseUser)
 	-> Result<(StatusCode, Json<Vec<Profile>>), StatusCode> {
@@ -152,52 +230,128 @@ async fn not_found(request: Request) {
 	}
 	
 }*/
+#[instrument(skip_all, fields(user = %redact(&auth.user_id)))]
 async fn get_match_data(State(db): State<DatabaseState>, auth: FirebaseUser)
 	-> Result<(StatusCode, Json<InitialMatchData>), StatusCode> {
 	
 	let id = Id::new(auth.user_id);
 	
-	let result = tokio::join!(
+	let (matches, messages, read_states) = tokio::join!(
 		db.get_initial_match_profiles(id.clone()),
-		db.get_initial_chat_messages(id.clone())
+		db.get_initial_chat_messages(id.clone(), InitialMatchData::MESSAGES_PER_MATCH),
+		db.get_read_states(id.clone())
 	);
 	
-	match result {
-		(Some(matches), Some(messages)) => {
-			println!("Getting user matches [{}]", id);
-			Ok((StatusCode::OK, Json(
-				InitialMatchData::new(matches, messages, &id))))
+	match matches {
+		Err(err) => {
+			error!(%err, "Error getting user matches");
+			Err(err.into())
 		},
-		(Some(matches), None) => {
-			println!("Error getting user messages (matches OK) [{}]", id);
-			Ok((StatusCode::OK, Json(
-				InitialMatchData::new(matches, Vec::new(), &id))))
+		Ok(matches) => {
+			
+			// Matches alone are still useful; the rest can be backfilled later
+			if let Err(err) = &messages {
+				warn!(%err, "Error getting user messages (matches OK)");
+			}
+			if let Err(err) = &read_states {
+				warn!(%err, "Error getting user read states (matches OK)");
+			}
+			
+			info!("Getting user matches");
+			Ok((StatusCode::OK, Json(InitialMatchData::new(
+				matches,
+				messages.unwrap_or_default(),
+				read_states.unwrap_or_default(),
+				&id
+			))))
+			
+		}
+	}
+	
+}
+
+#[instrument(skip_all, fields(user = %redact(&auth.user_id)))]
+async fn get_chat_history(State(db): State<DatabaseState>, auth: FirebaseUser, Path(with_id): Path<String>, Query(query): Query<ChatHistoryQuery>)
+	-> Result<(StatusCode, Json<ChatHistoryPage>), StatusCode> {
+	
+	let id = Id::new(auth.user_id);
+	let with_id = Id::new(with_id);
+	let limit = ChatHistoryPage::clamp_limit(query.limit);
+	
+	// Reports keep the messages as evidence, not for the blocked user to keep reading
+	match db.is_active_match(&id, &with_id).await {
+		Ok(true) => {},
+		Ok(false) => {
+			warn!("Rejecting chat history without active match");
+			return Err(StatusCode::NOT_FOUND);
 		},
-		_ => {
-			println!("Error getting user matches [{}]", id);
-			Err(StatusCode::UNAUTHORIZED)
+		Err(err) => {
+			error!(%err, "Error getting chat history");
+			return Err(err.into());
+		}
+	}
+	
+	let result = db.get_chat_messages(id.clone(), with_id, limit, query.before).await;
+	
+	match result {
+		Err(err) => {
+			error!(%err, "Error getting chat history");
+			Err(err.into())
+		},
+		Ok(messages) => {
+			info!("Getting chat history");
+			Ok((StatusCode::OK, Json(ChatHistoryPage::new(messages, limit, &id))))
 		}
 	}
 	
 }
 
+#[instrument(skip_all, fields(user = %redact(&auth.user_id)))]
+async fn unmatch_user(State(db): State<DatabaseState>, State(ws): State<WebSocketState>, auth: FirebaseUser, Path(to_id): Path<String>) -> StatusCode {
+	
+	let id = Id::new(auth.user_id);
+	
+	match handle_unmatch(db, ws, id, Id::new(to_id)).await {
+		Err(err) => err.into(),
+		// Same as NotMatched over the WebSocket
+		Ok(false) => StatusCode::NOT_FOUND,
+		Ok(true) => StatusCode::OK
+	}
+	
+}
+
+#[instrument(skip_all, fields(user = %redact(&auth.user_id)))]
+async fn report_user(State(db): State<DatabaseState>, State(ws): State<WebSocketState>, auth: FirebaseUser, Json(request): Json<ReportRequest>) -> StatusCode {
+	
+	let id = Id::new(auth.user_id);
+	let ReportRequest { to_id, reason, details, message_ids } = request;
+	
+	match handle_report(db, ws, id, Id::new(to_id), reason, details, message_ids).await {
+		Err(err) => err.into(),
+		Ok(_) => StatusCode::OK
+	}
+	
+}
+
+#[instrument(skip_all, fields(user = %redact(&auth.user_id)))]
 async fn read_user(State(db): State<DatabaseState>, auth: FirebaseUser) -> Result<(StatusCode, Json<User>), StatusCode> {
 	
 	let id = Id::new(auth.user_id);
 	let result = db.read_user(&id).await;
 	
 	match result {
-		None => {
-			println!("Error reading user [{}]", id);
-			Err(StatusCode::NOT_FOUND)
+		Err(err) => {
+			error!(%err, "Error reading user");
+			Err(err.into())
 		},
-		Some(user) => {
-			println!("Reading user [{}]", id);
+		Ok(user) => {
+			info!("Reading user");
 			Ok((StatusCode::OK, Json(user)))
 		}
 	}
 	
 }
+#[instrument(skip_all, fields(user = %redact(&auth.user_id)))]
 async fn write_user(State(db): State<DatabaseState>, auth: FirebaseUser, Json(mut user): Json<User>) -> StatusCode {
 	
 	//println!("{:?}", user);
@@ -209,86 +363,207 @@ async fn write_user(State(db): State<DatabaseState>, auth: FirebaseUser, Json(mu
 	let result = db.write_user(user).await;
 	
 	match result {
-		None => {
-			println!("Error writing to user [{}]: ", id);
-			StatusCode::UNAUTHORIZED
+		Err(err) => {
+			error!(%err, "Error writing to user");
+			err.into()
 		}
-		Some(_) => {
-			println!("Wrote to user [{}]", id);
+		Ok(_) => {
+			info!("Wrote to user");
 			StatusCode::OK
 		},
 	}
 	
 }
 
+// Replaces every preference, so null can set one back to "any"
+#[instrument(skip_all, fields(user = %redact(&auth.user_id)))]
+async fn write_preferences(State(db): State<DatabaseState>, auth: FirebaseUser, Json(preferences): Json<Preferences>) -> StatusCode {
+	
+	let id = Id::new(auth.user_id);
+	
+	match db.write_preferences(&id, preferences).await {
+		Err(err) => {
+			error!(%err, "Error writing preferences");
+			err.into()
+		},
+		Ok(_) => StatusCode::OK
+	}
+	
+}
+
 
-async fn ws_upgrade(State(db): State<DatabaseState>, State(ws): State<WebSocketState>, auth: FirebaseUser, request: WebSocketUpgrade) -> Response {
+#[instrument(skip_all, fields(user = %redact(&auth.user_id)))]
+async fn register_device(State(db): State<DatabaseState>, auth: FirebaseUser, Json(request): Json<DeviceTokenRequest>) -> StatusCode {
 	
 	let id = Id::new(auth.user_id);
-	println!("WebSocket upgrade [{}]", id);
 	
-	request.on_upgrade(move |socket| handle_socket(db, ws, id, socket))
+	match db.put_device_token(&id, request.token).await {
+		Err(err) => {
+			error!(%err, "Error registering device");
+			err.into()
+		},
+		Ok(_) => {
+			info!("Registered device");
+			StatusCode::OK
+		}
+	}
 	
 }
+#[instrument(skip_all, fields(user = %redact(&auth.user_id)))]
+async fn unregister_device(State(db): State<DatabaseState>, auth: FirebaseUser, Json(request): Json<DeviceTokenRequest>) -> StatusCode {
+	
+	let id = Id::new(auth.user_id);
+	
+	match db.delete_device_token(Some(&id), request.token).await {
+		Err(err) => {
+			error!(%err, "Error unregistering device");
+			err.into()
+		},
+		Ok(_) => {
+			info!("Unregistered device");
+			StatusCode::OK
+		}
+	}
+	
+}
+
 
-async fn handle_socket(db: DatabaseState, ws: WebSocketState, from_id: Id, socket: WebSocket) {
+#[instrument(skip_all, fields(user = %redact(&auth.user_id)))]
+async fn ws_upgrade(State(db): State<DatabaseState>, State(ws): State<WebSocketState>, auth: FirebaseUser, Query(query): Query<WebSocketQuery>, request: WebSocketUpgrade) -> Response {
 	
-	ws.clone().listen(from_id.clone(), socket, move |message| {
+	let id = Id::new(auth.user_id);
+	info!("WebSocket upgrade");
+	
+	// Only query params can be checked up front; a subprotocol is only known once selected
+	if Protocol::negotiate(None, query.version, query.encoding.as_deref()).is_none() {
+		warn!(version = query.version, encoding = query.encoding, "Unsupported protocol");
+		return StatusCode::BAD_REQUEST.into_response();
+	}
+	
+	request
+		.protocols(Protocol::SUBPROTOCOLS)
+		.on_upgrade(move |socket| {
+			
+			let subprotocol = socket.protocol().and_then(|protocol| protocol.to_str().ok());
+			let protocol = Protocol::negotiate(subprotocol, query.version, query.encoding.as_deref())
+				.unwrap_or(Protocol::DEFAULT);
+			
+			handle_socket(db, ws, id, socket, protocol, query.last_seq)
+			
+		})
+	
+}
+
+async fn handle_socket(db: DatabaseState, ws: WebSocketState, from_id: Id, socket: WebSocket, protocol: Protocol, last_seq: Option<i32>) {
+	
+	tokio::spawn(handle_presence(db.clone(), ws.clone(), from_id.clone(), true));
+	
+	let presence = (db.clone(), ws.clone(), from_id.clone());
+	ws.clone().listen(from_id.clone(), socket, protocol, last_seq, move |message, reply| {
 		
 		let (db, ws, from_id) = (db.clone(), ws.clone(), from_id.clone());
 		
-		match message {
-			IncomingMessage::QueueRefresh { blacklist } =>
-				tokio::spawn(async move {
-					handle_queue_refresh(db, ws, from_id, blacklist).await }),
-			IncomingMessage::Impression { to_id, liked } =>
-				tokio::spawn(async move {
-					handle_impression(db, ws, from_id, Id::new(to_id), liked).await }),
-			IncomingMessage::ChatMessage { to_id, content } =>
-				tokio::spawn(async move {
-					handle_chat_message(db, ws, from_id, Id::new(to_id), content).await }),
-		};
+		async move {
+			match message {
+				IncomingMessage::QueueRefresh { blacklist } =>
+					handle_queue_refresh(db, reply, from_id, blacklist).await,
+				IncomingMessage::Impression { to_id, liked } =>
+					handle_impression(db, ws, reply, from_id, Id::new(to_id), liked).await,
+				IncomingMessage::ChatMessage { to_id, content, nonce } =>
+					handle_chat_message(db, ws, reply, from_id, Id::new(to_id), content, nonce).await,
+				IncomingMessage::ChatHistory { with_id, before, limit } =>
+					handle_chat_history(db, reply, from_id, with_id, before, limit).await,
+				IncomingMessage::MarkRead { match_id, up_to } =>
+					handle_mark_read(db, ws, reply, from_id, Id::new(match_id), up_to).await,
+				IncomingMessage::Typing { to_id, typing } =>
+					handle_typing(db, ws, from_id, Id::new(to_id), typing).await,
+				IncomingMessage::OutboxAck { seq } =>
+					handle_outbox_ack(db, reply, from_id, seq).await,
+				IncomingMessage::Unmatch { to_id } => {
+					match handle_unmatch(db, ws, from_id, Id::new(to_id)).await {
+						Err(err) => reply.db_error(&err, "Couldn't unmatch").await,
+						Ok(false) => reply.error(ErrorCode::NotMatched, "No active match to unmatch").await,
+						Ok(true) => None
+					};
+				},
+				IncomingMessage::Report { to_id, reason, details, message_ids } => {
+					if let Err(err) = handle_report(db, ws, from_id, Id::new(to_id), reason, details, message_ids).await {
+						reply.db_error(&err, "Couldn't submit report").await;
+					}
+				}
+			}
+		}
 		
 	}).await;
 	
+	// Still online if another session is open
+	let (db, ws, from_id) = presence;
+	if !ws.has_id(&from_id).await {
+		handle_presence(db, ws, from_id, false).await;
+	}
+	
+}
+#[instrument(skip_all, fields(online))]
+async fn handle_presence(db: DatabaseState, ws: WebSocketState, id: Id, online: bool) {
+	
+	let (_, match_ids) = tokio::join!(
+		db.touch_last_seen(&id),
+		db.get_active_match_ids(&id)
+	);
+	
+	let Ok(match_ids) = match_ids else {
+		error!("Error getting presence recipients");
+		return;
+	};
+	
+	for match_id in match_ids {
+		ws.try_send(&match_id, OutgoingMessage::Presence { user_id: id.to_string(), online }).await;
+	}
+	
 }
-async fn handle_queue_refresh(db: DatabaseState, ws: WebSocketState, id: Id, blacklist: Option<Vec<String>>) {
+#[instrument(skip_all)]
+async fn handle_queue_refresh(db: DatabaseState, reply: Reply, id: Id, blacklist: Option<Vec<String>>) {
 	
 	let result = db.get_queue_profiles(&id, blacklist).await;
 	
 	match result {
 		
-		None => {
-			println!("Error getting user discovery candidates [{}]", id);
-			//Err(StatusCode::UNAUTHORIZED)
+		Err(err) => {
+			error!(%err, "Error getting user discovery candidates");
+			reply.db_error(&err, "Couldn't get discovery candidates").await;
 		},
-		Some(profiles) => {
-			println!("Getting user discovery candidates [{}]", id);
-			ws.send_soft(&id, OutgoingMessage::QueueRefresh { profiles }).await;
-			//Ok((StatusCode::OK, Json(profiles)))
+		Ok(profiles) => {
+			info!("Getting user discovery candidates");
+			reply.send(OutgoingMessage::QueueRefresh { profiles }).await;
 		}
 		
 	}
 	
 }
-async fn handle_impression(db: DatabaseState, ws: WebSocketState, from_id: Id, to_id: Id, liked: bool) {
+#[instrument(skip_all, fields(to = %redact(&to_id), liked))]
+async fn handle_impression(db: DatabaseState, ws: WebSocketState, reply: Reply, from_id: Id, to_id: Id, liked: bool) {
 	
 	//println!("Handling impression: {from_id} -> {to_id} | {liked}");
 	
 	if !liked {
-		db.set_match_state(&from_id, &to_id, MatchState::Dead).await;
+		if let Err(err) = db.set_match_state(&from_id, &to_id, MatchState::Dead).await {
+			reply.db_error(&err, "Couldn't save impression").await;
+		}
 	} else {
 		
 		let current_state = db.get_match_state(&from_id, &to_id).await;
 		
 		match current_state {
-			None => handle_pending_like(db, ws, from_id, to_id).await,
-			Some(MatchState::Pending(old_sender)) => {
+			Err(err) => {
+				reply.db_error(&err, "Couldn't save impression").await;
+			},
+			Ok(None) => handle_pending_like(db, ws, reply, from_id, to_id).await,
+			Ok(Some(MatchState::Pending(old_sender))) => {
 				
 				// Ensure that both liked each other, rather than one being duplicated
 				let new_sender = Sender::of(&from_id, &to_id);
 				if new_sender != old_sender {
-					handle_match(db, ws, from_id, to_id).await;
+					handle_match(db, ws, reply, from_id, to_id).await;
 				} else {
 					// Maybe log like duplication?
 				}
@@ -300,56 +575,237 @@ async fn handle_impression(db: DatabaseState, ws: WebSocketState, from_id: Id, t
 	}
 	
 }
-async fn handle_pending_like(db: DatabaseState, ws: WebSocketState, from_id: Id, to_id: Id) {
+async fn handle_pending_like(db: DatabaseState, ws: WebSocketState, reply: Reply, from_id: Id, to_id: Id) {
 	
-	println!("New pending like: [{}] -> [{}]", from_id, to_id);
+	info!("New pending like");
 	let new_state = MatchState::Pending(Sender::of(&from_id, &to_id));
 	
-	tokio::join!(
-		db.set_match_state(&from_id, &to_id, new_state),
-		ws.try_send(&to_id, OutgoingMessage::Like)
-	);
+	// Only tell them once it's actually been stored
+	if let Err(err) = db.set_match_state(&from_id, &to_id, new_state).await {
+		reply.db_error(&err, "Couldn't save like").await;
+		return;
+	}
+	ws.metrics().likes.inc();
+	
+	ws.try_send(&to_id, OutgoingMessage::Like).await;
 	
 }
-async fn handle_match(db: DatabaseState, ws: WebSocketState, from_id: Id, to_id: Id) {
+async fn handle_match(db: DatabaseState, ws: WebSocketState, reply: Reply, from_id: Id, to_id: Id) {
 	
-	let profiles = tokio::join!(
-		db.get_profile(&from_id),
-		db.get_profile(&to_id)
+	let users = tokio::join!(
+		db.get_user(&from_id),
+		db.get_user(&to_id)
 	);
 	
-	match profiles {
-		
-		(Some(sender), Some(receiver)) => {
-			println!("New match [{}] <-> [{}]", from_id, to_id);
-			tokio::join!(
-				db.set_match_state(&from_id, &to_id, MatchState::Active),
-				ws.try_send(&from_id, OutgoingMessage::Match { profile: receiver }),
-				ws.try_send(&to_id, OutgoingMessage::Match { profile: sender })
-			);
+	let (sender, receiver) = match users {
+		(Ok(sender), Ok(receiver)) => (sender, receiver),
+		(Ok(_), Err(err)) => {
+			error!(%err, "Match Error: Couldn't get receiver");
+			reply.db_error(&err, "Couldn't get match profiles").await;
+			return;
 		},
-		(Some(_), None) => println!("Match Error: Couldn't get receiver [{}]", to_id),
-		(None, Some(_)) => println!("Match Error: Couldn't get sender [{}]", from_id),
-		(None, None) => println!("Match Error: Couldn't get profiles [{}] [{}]", from_id, to_id)
-		
+		(Err(err), _) => {
+			error!(%err, "Match Error: Couldn't get sender");
+			reply.db_error(&err, "Couldn't get match profiles").await;
+			return;
+		}
+	};
+	
+	if let Err(err) = db.set_match_state(&from_id, &to_id, MatchState::Active).await {
+		reply.db_error(&err, "Couldn't save match").await;
+		return;
 	}
+	ws.metrics().likes.inc();
+	ws.metrics().matches.inc();
+	
+	info!("New match");
+	let receiver_profile = receiver.clone().to_profile(&sender);
+	let sender_profile = sender.to_profile(&receiver);
+	tokio::join!(
+		ws.try_send(&from_id, OutgoingMessage::Match { profile: receiver_profile }),
+		ws.try_send(&to_id, OutgoingMessage::Match { profile: sender_profile })
+	);
 	
 }
-async fn handle_chat_message(db: DatabaseState, ws: WebSocketState, from_id: Id, to_id: Id, content: String) {
+#[instrument(skip_all, fields(to = %redact(&to_id)))]
+async fn handle_chat_message(db: DatabaseState, ws: WebSocketState, reply: Reply, from_id: Id, to_id: Id, content: String, nonce: Option<String>) {
+	
+	let fail = |reason| OutgoingMessage::ChatFailed {
+		nonce: nonce.clone(),
+		to_id: to_id.to_string(),
+		reason
+	};
+	
+	// Only active matches can talk; blocking always kills the match, so this covers blocks too
+	match db.get_match_state(&from_id, &to_id).await {
+		Ok(Some(MatchState::Active)) => {},
+		Ok(_) => {
+			warn!("Rejecting chat message without active match");
+			reply.send(fail(ChatFailure::NotMatched)).await;
+			return;
+		},
+		Err(_) => {
+			reply.send(fail(ChatFailure::ServerError)).await;
+			return;
+		}
+	}
 	
 	let message_id = Uuid::new_v4().to_string();
 	
+	// Only deliver once stored, so both sides agree on the id and timestamp
+	let Ok(message) = db.put_chat_message(from_id.clone(), to_id.clone(), message_id, content).await else {
+		reply.send(fail(ChatFailure::ServerError)).await;
+		return;
+	};
+	ws.metrics().chat_messages.inc();
+	
 	tokio::join!(
-		// unfortunate clone of content and id here
+		reply.send(OutgoingMessage::ChatAck {
+			nonce,
+			message_id: message.id.clone(),
+			timestamp: message.timestamp.clone()
+		}),
 		ws.try_send(&to_id, OutgoingMessage::ChatMessage {
 			from_id: from_id.to_string(),
-			message_id: message_id.clone(),
-			content: content.clone()
-		}),
-		db.put_chat_message(from_id, to_id.clone(), message_id, content)
+			message_id: message.id,
+			timestamp: message.timestamp,
+			content: message.content
+		})
 	);
 	
 }
+#[instrument(skip_all, fields(to = %redact(&to_id)))]
+async fn handle_unmatch(db: DatabaseState, ws: WebSocketState, from_id: Id, to_id: Id) -> Result<bool, DbError> {
+	
+	let unmatched = db.unmatch(&from_id, &to_id).await;
+	
+	match &unmatched {
+		Err(err) => error!(%err, "Error unmatching"),
+		Ok(false) => warn!("Unmatch without active match"),
+		Ok(true) => {
+			info!("Unmatched");
+			ws.try_send(&to_id, OutgoingMessage::Unmatched { from_id: from_id.to_string() }).await;
+		}
+	}
+	
+	unmatched
+	
+}
+#[instrument(skip_all, fields(to = %redact(&to_id)))]
+async fn handle_report(db: DatabaseState, ws: WebSocketState, from_id: Id, to_id: Id,
+	reason: String, details: Option<String>, message_ids: Option<Vec<String>>) -> Result<(), DbError> {
+	
+	let report = Report::new(&from_id, &to_id, reason, details, message_ids);
+	let was_active = db.report(report).await;
+	
+	match &was_active {
+		Err(err) => error!(%err, "Error reporting"),
+		Ok(was_active) => {
+			info!("Reported and blocked");
+			if *was_active {
+				// Looks like any other unmatch from their side
+				ws.try_send(&to_id, OutgoingMessage::Unmatched { from_id: from_id.to_string() }).await;
+			}
+		}
+	}
+	
+	was_active.map(|_| ())
+	
+}
+#[instrument(skip_all, fields(to = %redact(&to_id)))]
+async fn handle_mark_read(db: DatabaseState, ws: WebSocketState, reply: Reply, from_id: Id, to_id: Id, up_to: String) {
+	
+	// The messages outlive blocks and unmatches, but receipts for them shouldn't
+	match db.is_active_match(&from_id, &to_id).await {
+		Ok(true) => {},
+		Ok(false) => {
+			warn!("Rejecting read receipt without active match");
+			reply.error(ErrorCode::NotMatched, "No active match to mark read").await;
+			return;
+		},
+		Err(err) => {
+			reply.db_error(&err, "Couldn't mark messages read").await;
+			return;
+		}
+	}
+	
+	match db.mark_read(&from_id, &to_id, up_to.clone()).await {
+		Ok(true) => {
+			ws.try_send(&to_id, OutgoingMessage::Read { from_id: from_id.to_string(), up_to }).await;
+		},
+		Ok(false) => {}, // stale
+		Err(DbError::NotFound) => {
+			reply.error(ErrorCode::NotFound, "No such message to mark read").await;
+		},
+		Err(err) => {
+			error!(%err, "Error marking read");
+			reply.db_error(&err, "Couldn't mark messages read").await;
+		}
+	}
+	
+}
+#[instrument(skip_all, fields(to = %redact(&to_id)))]
+async fn handle_typing(db: DatabaseState, ws: WebSocketState, from_id: Id, to_id: Id, typing: bool) {
+	
+	// Ephemeral; nothing here touches the database beyond the match check, which
+	// only runs once the sender is within their limit
+	if !ws.allow_typing_frame(&from_id) || !ws.has_id(&to_id).await {
+		return;
+	}
+	if !matches!(db.is_active_match(&from_id, &to_id).await, Ok(true)) {
+		return;
+	}
+	
+	// Only once it's known to be a real conversation, so other typing can't use up the limit
+	if ws.allow_typing(&from_id, &to_id, typing) {
+		ws.try_send(&to_id, OutgoingMessage::Typing { from_id: from_id.to_string(), typing }).await;
+	}
+	
+}
+#[instrument(skip_all, fields(with = %redact(&with_id)))]
+async fn handle_chat_history(db: DatabaseState, reply: Reply, id: Id, with_id: String, before: Option<String>, limit: Option<i64>) {
+	
+	let limit = ChatHistoryPage::clamp_limit(limit);
+	let other_id = Id::new(with_id.clone());
+	
+	match db.is_active_match(&id, &other_id).await {
+		Ok(true) => {},
+		Ok(false) => {
+			warn!("Rejecting chat history without active match");
+			reply.error(ErrorCode::NotMatched, "No active match to get history for").await;
+			return;
+		},
+		Err(err) => {
+			reply.db_error(&err, "Couldn't get chat history").await;
+			return;
+		}
+	}
+	
+	let result = db.get_chat_messages(id.clone(), other_id, limit, before).await;
+	
+	match result {
+		Err(err) => {
+			error!(%err, "Error getting chat history");
+			reply.db_error(&err, "Couldn't get chat history").await;
+		},
+		Ok(messages) => {
+			let page = ChatHistoryPage::new(messages, limit, &id);
+			reply.send(OutgoingMessage::ChatHistory { with_id, page }).await;
+		}
+	}
+	
+}
+#[instrument(skip_all, fields(seq))]
+async fn handle_outbox_ack(db: DatabaseState, reply: Reply, id: Id, seq: i32) {
+	
+	if let Err(err) = db.ack_outbox(&id, seq).await {
+		error!(%err, "Error acknowledging outbox");
+		reply.db_error(&err, "Couldn't acknowledge events").await;
+	}
+	
+}
+
+
 
 
 
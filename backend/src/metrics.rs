@@ -0,0 +1,117 @@
+use prometheus::{
+	Registry, Encoder, TextEncoder,
+	IntCounter, IntCounterVec, IntGauge, Histogram, HistogramVec, HistogramOpts, Opts
+};
+
+
+
+// Everything scraped from /metrics. The metric handles are cheap to clone and
+// share their values, like the rest of the app state.
+#[derive(Clone)]
+pub struct MetricsState {
+	registry: Registry,
+	
+	// Set at scrape time from WebSocketState
+	pub connected_users: IntGauge,
+	pub connected_sessions: IntGauge,
+	// By IncomingMessage variant
+	pub messages: IntCounterVec,
+	
+	// Per minute is left to the query, e.g. rate(likes_total[1m]) * 60
+	pub likes: IntCounter,
+	pub matches: IntCounter,
+	pub chat_messages: IntCounter,
+	
+	// Set at scrape time from the deadpool status
+	pub pool_size: IntGauge,
+	pub pool_available: IntGauge,
+	pub pool_waiting: IntGauge,
+	pub pool_wait: Histogram,
+	// By query name, as passed to DatabaseState::execute_result
+	pub query_duration: HistogramVec
+}
+
+impl Default for MetricsState {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl MetricsState {
+	
+	pub fn new() -> Self {
+		
+		let registry = Registry::new();
+		
+		let metrics = Self {
+			connected_users: IntGauge::new("ws_connected_users", "Users with at least one WebSocket session").unwrap(),
+			connected_sessions: IntGauge::new("ws_connected_sessions", "Open WebSocket sessions").unwrap(),
+			messages: IntCounterVec::new(
+				Opts::new("ws_messages_received_total", "WebSocket messages received, by type"),
+				&["type"]
+			).unwrap(),
+			
+			likes: IntCounter::new("likes_total", "Likes stored, including ones that made a match").unwrap(),
+			matches: IntCounter::new("matches_total", "Matches made").unwrap(),
+			chat_messages: IntCounter::new("chat_messages_total", "Chat messages stored").unwrap(),
+			
+			pool_size: IntGauge::new("db_pool_size", "Database connections currently open").unwrap(),
+			pool_available: IntGauge::new("db_pool_available", "Idle database connections").unwrap(),
+			pool_waiting: IntGauge::new("db_pool_waiting", "Queries waiting for a database connection").unwrap(),
+			pool_wait: Histogram::with_opts(
+				HistogramOpts::new("db_pool_wait_seconds", "Time spent waiting for a database connection")
+					.buckets(vec![0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0])
+			).unwrap(),
+			query_duration: HistogramVec::new(
+				HistogramOpts::new("db_query_duration_seconds", "Time spent running database queries, by query")
+					.buckets(vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0]),
+				&["query"]
+			).unwrap(),
+			
+			registry
+		};
+		
+		metrics.register_all();
+		metrics
+		
+	}
+	
+	fn register_all(&self) {
+		
+		use prometheus::core::Collector;
+		
+		let collectors: Vec<Box<dyn Collector>> = vec![
+			Box::new(self.connected_users.clone()),
+			Box::new(self.connected_sessions.clone()),
+			Box::new(self.messages.clone()),
+			Box::new(self.likes.clone()),
+			Box::new(self.matches.clone()),
+			Box::new(self.chat_messages.clone()),
+			Box::new(self.pool_size.clone()),
+			Box::new(self.pool_available.clone()),
+			Box::new(self.pool_waiting.clone()),
+			Box::new(self.pool_wait.clone()),
+			Box::new(self.query_duration.clone())
+		];
+		
+		// Names are all fixed above, so this can only fail on a duplicate
+		for collector in collectors {
+			self.registry.register(collector).expect("Duplicate metric");
+		}
+		
+	}
+	
+	// Prometheus text format, with its content type
+	pub fn encode(&self) -> (String, Vec<u8>) {
+		
+		let encoder = TextEncoder::new();
+		let mut buffer = Vec::new();
+		
+		// Can only fail on I/O, and this writes to memory
+		encoder.encode(&self.registry.gather(), &mut buffer).expect("Error encoding metrics");
+		
+		(encoder.format_type().to_string(), buffer)
+		
+	}
+	
+}
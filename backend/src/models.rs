@@ -1,22 +1,25 @@
 
 use crate::Id;
+use crate::geo;
 use crate::schema::*;
 
 use diesel::prelude::*;
 use diesel::backend::Backend;
 use diesel::expression::AsExpression;
-use diesel::sql_types::Integer;
+use diesel::sql_types::{Integer, BigInt, Text, Nullable};
 use diesel::deserialize::{self,	FromSql, FromSqlRow};
 use diesel::serialize::{self,	ToSql, Output};
 
 use serde::{Serialize, Deserialize};
 
+use time::{Date, OffsetDateTime, PrimitiveDateTime};
+use time::macros::format_description;
 
 
-fn empty_string() -> String { "".to_string() }
 
+fn empty_string() -> String { "".to_string() }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 #[derive(Serialize, Deserialize)]
 #[derive(Queryable, Selectable, Insertable)]
 #[derive(AsChangeset)]
@@ -55,7 +58,40 @@ pub struct User {
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub interests: Option<String>,
 	
+	/* PREFERENCES */
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub age_min: Option<i32>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub age_max: Option<i32>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub max_distance: Option<i32>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub seeking_genders: Option<String>,
+	
+	/* DERIVED */
+	// Maintained from latitude/longitude on write, never sent or accepted
+	#[serde(skip)]
+	pub geohash: Option<String>,
+	// Set on WebSocket connect/disconnect, only ever exposed coarsened
+	#[serde(skip)]
+	pub last_seen: Option<String>,
+	
+}
+// Discovery preferences, always written as a whole. Unlike User's changeset,
+// None clears a preference back to "any" instead of leaving it as it was.
+#[derive(Debug, Default, Clone)]
+#[derive(Deserialize)]
+#[derive(AsChangeset)]
+#[diesel(table_name = users)]
+#[diesel(treat_none_as_null = true)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct Preferences {
+	pub age_min: Option<i32>,
+	pub age_max: Option<i32>,
+	pub max_distance: Option<i32>,
+	pub seeking_genders: Option<String>
 }
+
 impl User {
 	
 	pub fn new(id: Id) -> Self {
@@ -68,32 +104,136 @@ impl User {
 			_ => None
 		}
 	}
+	pub fn update_geohash(&mut self) {
+		self.geohash = self.location().map(|(latitude, longitude)|
+			geo::encode(latitude as f64, longitude as f64, geo::GEOHASH_PRECISION));
+	}
 	
-	fn distance_to_user(&self, other: &User) -> Option<usize> {
-		// This is not even slightly accurate
-		// Aggressively ballparking until I get around to improving it
-		match (self.latitude, self.longitude, other.latitude, other.longitude) {
-			(Some(lat1), Some(long1), Some(lat2), Some(long2)) => {
-				let dlat = lat1 - lat2;
-				let dlong = long1 - long2;
-				Some((f32::sqrt(dlat * dlat + dlong * dlong)/60.0) as usize)
-			}
-			_ => None
+	pub fn distance_to_user(&self, other: &User) -> Option<usize> {
+		
+		let (lat1, long1) = self.location()?;
+		let (lat2, long2) = other.location()?;
+		let distance = geo::distance_km(lat1, long1, lat2, long2);
+		
+		// Bucket to whole kilometres (rounding up, never 0) so repeated
+		// lookups can't be used to triangulate someone's exact location
+		Some((distance.ceil() as usize).max(1))
+		
+	}
+	pub fn age(&self) -> Option<usize> {
+		
+		let format = format_description!("[year]-[month]-[day]");
+		
+		// Tolerate full timestamps by only looking at the date portion
+		let birth_date = self.birth_date.as_ref()?;
+		let birth_date = Date::parse(birth_date.get(..10)?, &format).ok()?;
+		let today = OffsetDateTime::now_utc().date();
+		
+		let mut age = today.year() - birth_date.year();
+		if (today.month() as u8, today.day()) < (birth_date.month() as u8, birth_date.day()) {
+			age -= 1;
+		}
+		
+		usize::try_from(age).ok()
+		
+	}
+	// ISO dates bounding birth_date for this user's age preference, as
+	// (born after, born on or before), so the age range can be prefiltered in SQL
+	pub fn birth_date_range(&self) -> (Option<String>, Option<String>) {
+		
+		let today = OffsetDateTime::now_utc().date();
+		let years_ago = |years: i32| {
+			let year = today.year() - years;
+			today.replace_year(year)
+				.or_else(|_| today.replace_day(28).and_then(|date| date.replace_year(year)))
+				.ok()
+				.map(|date| date.to_string())
+		};
+		
+		(
+			self.age_max.and_then(|max| years_ago(max + 1)),
+			self.age_min.and_then(years_ago)
+		)
+		
+	}
+	
+	fn seeks_gender(&self, gender: Option<&String>) -> bool {
+		
+		let Some(seeking) = &self.seeking_genders else {
+			return true; // no preference
+		};
+		let Some(gender) = gender else {
+			return false;
+		};
+		
+		seeking
+			.split(',')
+			.map(str::trim)
+			.any(|seeking| seeking.eq_ignore_ascii_case(gender.trim()))
+		
+	}
+	
+	// Whether `other` fits this user's discovery preferences.
+	// An unknown age/location never satisfies a preference that has been set.
+	pub fn accepts(&self, other: &User) -> bool {
+		
+		let age = other.age();
+		let age_ok = match (self.age_min, self.age_max) {
+			(None, None) => true,
+			(min, max) => age.is_some_and(|age| {
+				let age = age as i32;
+				min.is_none_or(|min| age >= min) && max.is_none_or(|max| age <= max)
+			})
+		};
+		
+		let distance_ok = match self.max_distance {
+			None => true,
+			Some(max) => self.distance_to_user(other)
+				.is_some_and(|distance| distance as i32 <= max)
+		};
+		
+		age_ok && distance_ok && self.seeks_gender(other.gender_identity.as_ref())
+		
+	}
+	pub fn accepts_mutually(&self, other: &User) -> bool {
+		self.accepts(other) && other.accepts(self)
+	}
+	
+	fn last_seen_bucket(&self) -> Option<LastSeen> {
+		
+		let format = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+		
+		let last_seen = self.last_seen.as_ref()?;
+		let last_seen = PrimitiveDateTime::parse(last_seen, &format).ok()?.assume_utc();
+		let elapsed = OffsetDateTime::now_utc() - last_seen;
+		
+		if elapsed.whole_days() < 1 {
+			Some(LastSeen::Today)
+		} else if elapsed.whole_days() < 7 {
+			Some(LastSeen::ThisWeek)
+		} else if elapsed.whole_days() < 30 {
+			Some(LastSeen::ThisMonth)
+		} else {
+			None
 		}
 		
 	}
-	pub fn to_profile(self/* , for_user: &User */) -> Profile {
+	
+	pub fn to_profile(self, for_user: &User) -> Profile {
 		
-		//let distance = self.distance_to_user(for_user);
+		let distance = self.distance_to_user(for_user);
+		let age = self.age();
+		let last_seen = self.last_seen_bucket();
 		
 		Profile {
 			
 			id: self.id,
 			
 			/* DERIVED */
-			// Calculating these in a secure way is honestly very annoying
-			distance: None,
-			age: None,
+			// Never expose location/birth date/activity directly, only these
+			distance,
+			age,
+			last_seen,
 			
 			/* VITALS */
 			name: self.name,
@@ -122,11 +262,13 @@ pub struct Profile {
 	pub id: String,
 	
 	/* DERIVED */
-	// distance?
+	// distance in whole kilometres
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub distance: Option<usize>,
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub age: Option<usize>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub last_seen: Option<LastSeen>,
 	
 	/* VITALS */
 	#[serde(skip_serializing_if = "Option::is_none")]
@@ -150,6 +292,16 @@ pub struct Profile {
 }
 
 
+#[derive(Debug)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LastSeen {
+	Today,
+	ThisWeek,
+	ThisMonth
+}
+
+
 
 #[derive(Debug, Clone, PartialEq)]
 #[derive(AsExpression, FromSqlRow)]
@@ -261,7 +413,7 @@ impl Into<MatchState> for i32 {
 			2 => MatchState::Pending(Sender::One),
 			3 => MatchState::Pending(Sender::Two),
 			invalid => {
-				println!("Invalid match state: {}", invalid);
+				tracing::warn!(invalid, "Invalid match state");
 				MatchState::Dead
 			}
 		}
@@ -338,7 +490,7 @@ impl Match {
 
 #[derive(Debug)]
 //#[derive(Serialize, Deserialize)]
-#[derive(Queryable, Selectable, Insertable)]
+#[derive(Queryable, QueryableByName, Selectable, Insertable)]
 #[diesel(table_name = messages)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 //#[serde(deny_unknown_fields, rename_all = "camelCase")]
@@ -366,7 +518,7 @@ impl ChatMessage {
 		} else if user_id == self.user2 {
 			&self.user1
 		} else {
-			println!("ChatMessage other_user error; neither user present: {} | {:?}", user_id, self);
+			tracing::warn!(user = %crate::id::redact(&user_id), "ChatMessage other_user error; neither user present");
 			&self.user1
 		}
 		
@@ -376,6 +528,56 @@ impl ChatMessage {
 
 
 
+// Read state of one active match, from the point of view of one of its users
+#[derive(Debug)]
+#[derive(Serialize)]
+#[derive(QueryableByName)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadState {
+	
+	#[diesel(sql_type = Text)]
+	pub user: String,
+	
+	#[diesel(sql_type = BigInt)]
+	pub unread_count: i64,
+	// Last message the other user has read
+	#[diesel(sql_type = Nullable<Text>)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub seen_up_to: Option<String>
+	
+}
+
+
+#[derive(Debug)]
+#[derive(Insertable)]
+#[diesel(table_name = reports)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Report {
+	
+	pub reporter: String,
+	pub reported: String,
+	
+	pub reason: String,
+	pub details: Option<String>,
+	pub message_ids: Option<String>
+	
+}
+impl Report {
+	
+	pub fn new(reporter: &Id, reported: &Id, reason: String, details: Option<String>, message_ids: Option<Vec<String>>) -> Self {
+		Self {
+			reporter: reporter.to_string(),
+			reported: reported.to_string(),
+			reason,
+			details,
+			message_ids: message_ids.map(|ids| ids.join(","))
+		}
+	}
+	
+}
+
+
+
 /*
 pub enum MatchState {
 	Dead,
@@ -501,3 +703,136 @@ impl Profile {
 	
 }
 */
+
+
+#[cfg(test)]
+mod tests {
+	
+	use super::*;
+	
+	// Birth date `years` years before today, shifted by `days`
+	fn born(years: i32, days: i64) -> Option<String> {
+		let today = OffsetDateTime::now_utc().date();
+		let date = today.replace_year(today.year() - years)
+			.or_else(|_| today.replace_day(28).and_then(|date| date.replace_year(today.year() - years)))
+			.unwrap();
+		Some((date + time::Duration::days(days)).to_string())
+	}
+	
+	fn user(id: &str, birth_date: Option<String>) -> User {
+		User { birth_date, ..User::new(Id::new(id.to_string())) }
+	}
+	
+	// What the SQL prefilter does with birth_date_range
+	fn in_range(viewer: &User, other: &User) -> bool {
+		let (after, on_or_before) = viewer.birth_date_range();
+		let birth_date = other.birth_date.as_ref().unwrap().get(..10).unwrap();
+		after.is_none_or(|after| birth_date > after.as_str()) && on_or_before.is_none_or(|before| birth_date <= before.as_str())
+	}
+	
+	fn timestamped(birth_date: Option<String>) -> Option<String> {
+		birth_date.map(|date| format!("{date}T12:00:00Z"))
+	}
+	
+	#[test]
+	fn age_boundaries_on_birthdays() {
+		
+		let viewer = User { age_min: Some(18), age_max: Some(30), ..user("viewer", None) };
+		
+		let turns_18_today = user("a", born(18, 0));
+		let turns_18_tomorrow = user("b", born(18, 1));
+		let turns_31_tomorrow = user("c", born(31, 1));
+		let turns_31_today = user("d", born(31, 0));
+		
+		assert_eq!(turns_18_today.age(), Some(18));
+		assert_eq!(turns_18_tomorrow.age(), Some(17));
+		assert_eq!(turns_31_tomorrow.age(), Some(30));
+		assert_eq!(turns_31_today.age(), Some(31));
+		
+		// Full timestamps only count by their date
+		let timestamped_18_today = user("e", timestamped(born(18, 0)));
+		let timestamped_31_today = user("f", timestamped(born(31, 0)));
+		assert_eq!(timestamped_18_today.age(), Some(18));
+		
+		for (other, expected) in [
+			(&turns_18_today, true),
+			(&turns_18_tomorrow, false),
+			(&turns_31_tomorrow, true),
+			(&turns_31_today, false),
+			(&timestamped_18_today, true),
+			(&timestamped_31_today, false)
+		] {
+			assert_eq!(viewer.accepts(other), expected, "{:?}", other.birth_date);
+			// The SQL prefilter has to agree exactly
+			assert_eq!(in_range(&viewer, other), expected, "{:?}", other.birth_date);
+		}
+		
+	}
+	
+	#[test]
+	fn no_preferences_accept_anyone() {
+		
+		let viewer = user("viewer", None);
+		let unknown = user("other", None);
+		
+		assert!(viewer.accepts(&unknown));
+		assert_eq!(viewer.birth_date_range(), (None, None));
+		
+	}
+	
+	#[test]
+	fn set_preferences_reject_unknowns() {
+		
+		let unknown = user("other", None);
+		
+		assert!(!User { age_min: Some(18), ..user("viewer", None) }.accepts(&unknown));
+		assert!(!User { max_distance: Some(50), ..user("viewer", None) }.accepts(&unknown));
+		assert!(!User { seeking_genders: Some("woman".to_string()), ..user("viewer", None) }.accepts(&unknown));
+		
+	}
+	
+	#[test]
+	fn distance_and_gender_preferences() {
+		
+		let viewer = User {
+			latitude: Some(51.5074),
+			longitude: Some(-0.1278),
+			// Oxford is about 83km away, Paris about 344km
+			max_distance: Some(100),
+			seeking_genders: Some("Woman, Non-binary".to_string()),
+			..user("viewer", None)
+		};
+		let nearby = User {
+			latitude: Some(51.7520),
+			longitude: Some(-1.2577),
+			gender_identity: Some("non-binary".to_string()),
+			..user("nearby", None)
+		};
+		let far = User { latitude: Some(48.8566), longitude: Some(2.3522), ..nearby.clone() };
+		let man = User { gender_identity: Some("man".to_string()), ..nearby.clone() };
+		
+		assert!(viewer.accepts(&nearby));
+		assert!(!viewer.accepts(&far));
+		assert!(!viewer.accepts(&man));
+		
+	}
+	
+	#[test]
+	fn mutual_acceptance_needs_both_sides() {
+		
+		let picky = User { age_min: Some(25), ..user("picky", born(30, 0)) };
+		let younger = user("younger", born(22, 0));
+		let older = user("older", born(40, 0));
+		
+		// Neither younger nor older has preferences, so only picky's decide
+		assert!(younger.accepts(&picky));
+		assert!(!picky.accepts(&younger));
+		assert!(!younger.accepts_mutually(&picky));
+		assert!(!picky.accepts_mutually(&younger));
+		
+		assert!(picky.accepts_mutually(&older));
+		assert!(older.accepts_mutually(&picky));
+		
+	}
+	
+}
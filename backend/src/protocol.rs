@@ -0,0 +1,477 @@
+use crate::ws::{IncomingMessage, OutgoingMessage, Message};
+
+use serde::{Serialize, Deserialize};
+
+use std::fmt;
+
+
+
+// Optional `requestId` alongside the message's own fields, echoed on any replies
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IncomingEnvelope {
+	pub request_id: Option<String>,
+	#[serde(flatten)]
+	pub message: IncomingMessage
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OutgoingEnvelope<'a> {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	request_id: Option<&'a str>,
+	#[serde(flatten)]
+	message: &'a OutgoingMessage
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+	Json(serde_json::Error),
+	MessagePackEncode(rmp_serde::encode::Error),
+	MessagePackDecode(rmp_serde::decode::Error),
+	// A text frame on a binary session, or the other way around
+	FrameType
+}
+
+impl fmt::Display for CodecError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			CodecError::Json(err) => write!(f, "{}", err),
+			CodecError::MessagePackEncode(err) => write!(f, "{}", err),
+			CodecError::MessagePackDecode(err) => write!(f, "{}", err),
+			CodecError::FrameType => write!(f, "unexpected frame type for this session's encoding")
+		}
+	}
+}
+
+// Carries whatever `requestId` could still be read, so the error can be matched to its request
+pub type DecodeError = (Option<String>, CodecError);
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+	// Clients from before versioning, which never negotiate
+	Legacy,
+	V1
+}
+
+// How frames are serialized. Independent of the version, which decides the message shapes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+	Json,
+	// Binary frames; much smaller for photo-heavy queue refreshes
+	MessagePack
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protocol {
+	pub version: ProtocolVersion,
+	pub encoding: Encoding
+}
+
+impl Protocol {
+	
+	// Preferred first; binary is only ever offered by clients that opted in
+	pub const SUBPROTOCOLS: [&'static str; 2] = ["nemesis.v1.msgpack", "nemesis.v1"];
+	
+	// Clients that don't negotiate at all predate versioning
+	pub const DEFAULT: Self = Self { version: ProtocolVersion::Legacy, encoding: Encoding::Json };
+	
+	pub fn from_subprotocol(subprotocol: &str) -> Option<Self> {
+		
+		let (version, encoding) = match subprotocol.strip_prefix("nemesis.") {
+			Some("v1") => (ProtocolVersion::V1, Encoding::Json),
+			Some("v1.msgpack") => (ProtocolVersion::V1, Encoding::MessagePack),
+			_ => return None
+		};
+		
+		Some(Self { version, encoding })
+		
+	}
+	
+	// A negotiated subprotocol wins over the query params. None if the client
+	// asked for something we don't speak.
+	pub fn negotiate(subprotocol: Option<&str>, version: Option<u32>, encoding: Option<&str>) -> Option<Self> {
+		
+		if let Some(subprotocol) = subprotocol {
+			return Self::from_subprotocol(subprotocol);
+		}
+		
+		let version = match version {
+			Some(version) => ProtocolVersion::from_number(version)?,
+			None => Self::DEFAULT.version
+		};
+		let encoding = match encoding {
+			Some(encoding) => Encoding::from_name(encoding)?,
+			None => Self::DEFAULT.encoding
+		};
+		
+		Some(Self { version, encoding })
+		
+	}
+	
+	pub fn decode(&self, frame: &Message) -> Result<IncomingEnvelope, DecodeError> {
+		
+		let value = self.encoding.decode(frame).map_err(|err| (None, err))?;
+		
+		// The request id is read separately first, so it survives the message being invalid
+		let request_id = value
+			.get("requestId")
+			.and_then(|request_id| request_id.as_str())
+			.map(str::to_string);
+		
+		self.version.codec().decode(value).map_err(|err| (request_id, CodecError::Json(err)))
+		
+	}
+	
+	pub fn encode(&self, message: &OutgoingMessage, request_id: Option<&str>) -> Result<Message, CodecError> {
+		
+		let value = self.version.codec().encode(message, request_id).map_err(CodecError::Json)?;
+		
+		self.encoding.encode(&value)
+		
+	}
+	
+	// Whether clients on this version understand the message at all
+	pub fn supports(&self, message: &OutgoingMessage) -> bool {
+		self.version.codec().supports(message)
+	}
+	
+	pub fn acknowledges_outbox(&self) -> bool {
+		self.version.codec().acknowledges_outbox()
+	}
+	
+	// Outbox events are stored in the latest version's shape, and each codec
+	// reshapes them when they're sent
+	pub fn store(message: &OutgoingMessage) -> Result<serde_json::Value, CodecError> {
+		ProtocolVersion::LATEST.codec().encode(message, None).map_err(CodecError::Json)
+	}
+	
+}
+
+impl ProtocolVersion {
+	
+	pub const LATEST: Self = Self::V1;
+	
+	pub fn from_number(version: u32) -> Option<Self> {
+		match version {
+			0 => Some(Self::Legacy),
+			1 => Some(Self::V1),
+			_ => None
+		}
+	}
+	
+	pub fn codec(self) -> &'static dyn Codec {
+		match self {
+			Self::Legacy => &LegacyCodec,
+			Self::V1 => &V1Codec
+		}
+	}
+	
+}
+
+impl Encoding {
+	
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name {
+			"json" => Some(Self::Json),
+			"msgpack" => Some(Self::MessagePack),
+			_ => None
+		}
+	}
+	
+	fn decode(&self, frame: &Message) -> Result<serde_json::Value, CodecError> {
+		match (self, frame) {
+			(Encoding::Json, Message::Text(data)) =>
+				serde_json::from_str(data).map_err(CodecError::Json),
+			(Encoding::MessagePack, Message::Binary(data)) =>
+				rmp_serde::from_slice(data).map_err(CodecError::MessagePackDecode),
+			_ => Err(CodecError::FrameType)
+		}
+	}
+	
+	fn encode(&self, value: &serde_json::Value) -> Result<Message, CodecError> {
+		match self {
+			Encoding::Json =>
+				serde_json::to_string(value).map(Message::Text).map_err(CodecError::Json),
+			// Named, so fields come out as a map like in JSON rather than a positional array
+			Encoding::MessagePack =>
+				rmp_serde::to_vec_named(value).map(Message::Binary).map_err(CodecError::MessagePackEncode)
+		}
+	}
+	
+}
+
+
+// Message shapes for one protocol version, on top of any encoding. New message
+// variants only need handling in older codecs, which can leave out whatever
+// their clients can't read.
+pub trait Codec: Send + Sync {
+	
+	fn decode(&self, value: serde_json::Value) -> serde_json::Result<IncomingEnvelope>;
+	
+	fn encode(&self, message: &OutgoingMessage, request_id: Option<&str>) -> serde_json::Result<serde_json::Value>;
+	
+	fn supports(&self, _message: &OutgoingMessage) -> bool {
+		true
+	}
+	
+	// Whether clients send OutboxAck for replayed events
+	fn acknowledges_outbox(&self) -> bool {
+		true
+	}
+	
+}
+
+// What clients understood before versioning, and before Outbox wrapping or
+// request ids. Anything else is left out for them.
+pub struct LegacyCodec;
+
+impl LegacyCodec {
+	
+	const TYPES: [&'static str; 4] = ["queueRefresh", "like", "match", "chatMessage"];
+	
+	// Fields added since: chatMessage.timestamp, and Profile.lastSeen
+	fn reshape(mut value: serde_json::Value) -> serde_json::Value {
+		
+		let remove = |value: &mut serde_json::Value, field| {
+			if let Some(fields) = value.as_object_mut() {
+				fields.remove(field);
+			}
+		};
+		
+		match value["type"].as_str() {
+			Some("chatMessage") => remove(&mut value, "timestamp"),
+			Some("match") => remove(&mut value, "lastSeen"),
+			Some("queueRefresh") => {
+				if let Some(profiles) = value["profiles"].as_array_mut() {
+					profiles.iter_mut().for_each(|profile| remove(profile, "lastSeen"));
+				}
+			},
+			_ => {}
+		}
+		
+		value
+		
+	}
+	
+}
+
+impl Codec for LegacyCodec {
+	
+	fn decode(&self, value: serde_json::Value) -> serde_json::Result<IncomingEnvelope> {
+		serde_json::from_value(value)
+	}
+	
+	fn encode(&self, message: &OutgoingMessage, _request_id: Option<&str>) -> serde_json::Result<serde_json::Value> {
+		
+		// Replays go out as the original event
+		let value = match message {
+			OutgoingMessage::Outbox { event, .. } => event.clone(),
+			message => serde_json::to_value(message)?
+		};
+		
+		Ok(Self::reshape(value))
+		
+	}
+	
+	fn supports(&self, message: &OutgoingMessage) -> bool {
+		
+		let kind = match message {
+			OutgoingMessage::Outbox { event, .. } => event["type"].as_str(),
+			message => Some(message.kind())
+		};
+		
+		kind.is_some_and(|kind| Self::TYPES.contains(&kind))
+		
+	}
+	
+	fn acknowledges_outbox(&self) -> bool {
+		false
+	}
+	
+}
+
+pub struct V1Codec;
+
+impl Codec for V1Codec {
+	
+	fn decode(&self, value: serde_json::Value) -> serde_json::Result<IncomingEnvelope> {
+		serde_json::from_value(value)
+	}
+	
+	fn encode(&self, message: &OutgoingMessage, request_id: Option<&str>) -> serde_json::Result<serde_json::Value> {
+		serde_json::to_value(OutgoingEnvelope { request_id, message })
+	}
+	
+}
+
+
+#[cfg(test)]
+mod tests {
+	
+	use super::*;
+	
+	use crate::models::{Profile, LastSeen};
+	
+	use serde_json::json;
+	
+	fn v1(encoding: Encoding) -> Protocol {
+		Protocol { version: ProtocolVersion::V1, encoding }
+	}
+	
+	fn chat_message() -> OutgoingMessage {
+		OutgoingMessage::ChatMessage {
+			from_id: "a".to_string(),
+			message_id: "m".to_string(),
+			timestamp: "2024-06-12 02:12:04".to_string(),
+			content: "hi".to_string()
+		}
+	}
+	
+	fn profile() -> Profile {
+		Profile {
+			id: "a".to_string(),
+			distance: None,
+			age: Some(30),
+			last_seen: Some(LastSeen::Today),
+			name: Some("Alice".to_string()),
+			gender_identity: None,
+			pronouns: None,
+			bio: None,
+			looking_for: None,
+			interests: None,
+			photos: None
+		}
+	}
+	
+	fn json_of(frame: Message) -> serde_json::Value {
+		match frame {
+			Message::Text(data) => serde_json::from_str(&data).unwrap(),
+			_ => panic!("expected a text frame")
+		}
+	}
+	
+	#[test]
+	fn negotiates() {
+		
+		assert_eq!(Protocol::negotiate(None, None, None), Some(Protocol::DEFAULT));
+		assert_eq!(Protocol::DEFAULT.version, ProtocolVersion::Legacy);
+		
+		assert_eq!(Protocol::negotiate(None, Some(1), None), Some(v1(Encoding::Json)));
+		assert_eq!(Protocol::negotiate(None, Some(1), Some("msgpack")), Some(v1(Encoding::MessagePack)));
+		assert_eq!(Protocol::negotiate(None, Some(2), None), None);
+		assert_eq!(Protocol::negotiate(None, None, Some("xml")), None);
+		
+		// The subprotocol wins over the query params
+		assert_eq!(Protocol::negotiate(Some("nemesis.v1.msgpack"), Some(0), Some("json")), Some(v1(Encoding::MessagePack)));
+		assert_eq!(Protocol::negotiate(Some("nemesis.v2"), None, None), None);
+		
+	}
+	
+	#[test]
+	fn decodes_with_request_id() {
+		
+		let protocol = v1(Encoding::Json);
+		
+		let frame = Message::Text(json!({ "type": "typing", "toId": "b", "typing": true, "requestId": "r1" }).to_string());
+		let envelope = protocol.decode(&frame).unwrap();
+		assert_eq!(envelope.request_id.as_deref(), Some("r1"));
+		assert!(matches!(envelope.message, IncomingMessage::Typing { ref to_id, typing: true } if to_id == "b"));
+		
+		// The request id survives the message being invalid
+		let frame = Message::Text(json!({ "type": "typing", "requestId": "r2" }).to_string());
+		let Err((request_id, CodecError::Json(_))) = protocol.decode(&frame) else {
+			panic!("expected a decode error");
+		};
+		assert_eq!(request_id.as_deref(), Some("r2"));
+		
+		let frame = Message::Binary(vec![0x80]);
+		assert!(matches!(protocol.decode(&frame), Err((None, CodecError::FrameType))));
+		
+	}
+	
+	#[test]
+	fn encodes_with_request_id() {
+		
+		let protocol = v1(Encoding::Json);
+		
+		let value = json_of(protocol.encode(&OutgoingMessage::Like, Some("r1")).unwrap());
+		assert_eq!(value, json!({ "type": "like", "requestId": "r1" }));
+		
+		let value = json_of(protocol.encode(&OutgoingMessage::Like, None).unwrap());
+		assert_eq!(value, json!({ "type": "like" }));
+		
+	}
+	
+	#[test]
+	fn round_trips_message_pack() {
+		
+		let protocol = v1(Encoding::MessagePack);
+		
+		let Message::Binary(data) = protocol.encode(&chat_message(), Some("r1")).unwrap() else {
+			panic!("expected a binary frame");
+		};
+		let value: serde_json::Value = rmp_serde::from_slice(&data).unwrap();
+		assert_eq!(value["type"], "chatMessage");
+		assert_eq!(value["fromId"], "a");
+		assert_eq!(value["requestId"], "r1");
+		
+		let frame = Message::Binary(rmp_serde::to_vec_named(&json!({ "type": "queueRefresh", "requestId": "r2" })).unwrap());
+		let envelope = protocol.decode(&frame).unwrap();
+		assert_eq!(envelope.request_id.as_deref(), Some("r2"));
+		assert!(matches!(envelope.message, IncomingMessage::QueueRefresh { blacklist: None }));
+		
+		let frame = Message::Text("{}".to_string());
+		assert!(matches!(protocol.decode(&frame), Err((None, CodecError::FrameType))));
+		
+	}
+	
+	#[test]
+	fn legacy_only_gets_legacy_messages() {
+		
+		let protocol = Protocol::DEFAULT;
+		
+		assert!(protocol.supports(&OutgoingMessage::Like));
+		assert!(protocol.supports(&chat_message()));
+		assert!(!protocol.supports(&OutgoingMessage::Typing { from_id: "a".to_string(), typing: true }));
+		assert!(!protocol.acknowledges_outbox());
+		
+		// Original shape, without a timestamp or request id
+		let value = json_of(protocol.encode(&chat_message(), Some("r1")).unwrap());
+		assert_eq!(value, json!({ "type": "chatMessage", "fromId": "a", "messageId": "m", "content": "hi" }));
+		
+		// Nor when profiles were last seen
+		let expected = json!({ "id": "a", "age": 30, "name": "Alice" });
+		let value = json_of(protocol.encode(&OutgoingMessage::Match { profile: profile() }, None).unwrap());
+		assert_eq!(value, json!({ "type": "match", "id": "a", "age": 30, "name": "Alice" }));
+		let value = json_of(protocol.encode(&OutgoingMessage::QueueRefresh { profiles: vec![profile(), profile()] }, None).unwrap());
+		assert_eq!(value, json!({ "type": "queueRefresh", "profiles": [expected, expected] }));
+		
+		let value = json_of(v1(Encoding::Json).encode(&OutgoingMessage::Match { profile: profile() }, None).unwrap());
+		assert_eq!(value["lastSeen"], "today");
+		
+	}
+	
+	#[test]
+	fn replays_stored_events_per_version() {
+		
+		let event = Protocol::store(&chat_message()).unwrap();
+		let replay = OutgoingMessage::Outbox { seq: 3, event };
+		
+		let value = json_of(v1(Encoding::Json).encode(&replay, None).unwrap());
+		assert_eq!(value["type"], "outbox");
+		assert_eq!(value["seq"], 3);
+		assert_eq!(value["event"]["timestamp"], "2024-06-12 02:12:04");
+		
+		// Legacy clients get the bare event, reshaped
+		assert!(Protocol::DEFAULT.supports(&replay));
+		let value = json_of(Protocol::DEFAULT.encode(&replay, None).unwrap());
+		assert_eq!(value, json!({ "type": "chatMessage", "fromId": "a", "messageId": "m", "content": "hi" }));
+		
+		let event = serde_json::to_value(OutgoingMessage::Unmatched { from_id: "a".to_string() }).unwrap();
+		assert!(!Protocol::DEFAULT.supports(&OutgoingMessage::Outbox { seq: 4, event }));
+		
+	}
+	
+}
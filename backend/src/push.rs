@@ -0,0 +1,352 @@
+use crate::Id;
+use crate::db::DatabaseState;
+use crate::ws::OutgoingMessage;
+use crate::id::redact;
+
+use std::sync::Arc;
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use tracing::{info, warn};
+
+use google_fcm1::FirebaseCloudMessaging;
+use google_fcm1::api::{Message, Notification, SendMessageRequest};
+use google_fcm1::{oauth2, hyper, hyper_rustls};
+
+
+
+// Longest chat message preview shown in a notification
+const PREVIEW_LENGTH: usize = 100;
+
+#[derive(Debug, Clone)]
+pub struct Push {
+	pub title: String,
+	pub body: String,
+	// The OutgoingMessage's `type` and ids only, for the client to fetch the rest.
+	// Whole messages can go over FCM's 4 KB limit.
+	pub data: HashMap<String, String>
+}
+
+impl Push {
+	
+	// Only the events worth waking someone's phone for
+	pub fn from_outgoing(message: &OutgoingMessage) -> Option<Self> {
+		
+		let (title, body, data) = match message {
+			OutgoingMessage::Like => (
+				"Someone liked you!".to_string(),
+				"Start swiping to find out who".to_string(),
+				vec![("type", "like")]
+			),
+			OutgoingMessage::Match { profile } => (
+				"It's a match!".to_string(),
+				match &profile.name {
+					Some(name) => format!("You matched with {name}"),
+					None => "You have a new match".to_string()
+				},
+				vec![("type", "match"), ("userId", profile.id.as_str())]
+			),
+			OutgoingMessage::ChatMessage { from_id, message_id, content, .. } => (
+				"New message".to_string(),
+				content.chars().take(PREVIEW_LENGTH).collect(),
+				vec![("type", "chatMessage"), ("fromId", from_id.as_str()), ("messageId", message_id.as_str())]
+			),
+			_ => return None
+		};
+		
+		let data = data
+			.into_iter()
+			.map(|(key, value)| (key.to_string(), value.to_string()))
+			.collect();
+		
+		Some(Self { title, body, data })
+		
+	}
+	
+}
+
+
+#[derive(Debug)]
+pub enum PushError {
+	// The token is stale or was never valid, and should be forgotten
+	InvalidToken,
+	Failed(String)
+}
+
+#[async_trait]
+pub trait PushSender: Send + Sync {
+	async fn send(&self, token: &str, push: &Push) -> Result<(), PushError>;
+}
+
+
+type Connector = hyper_rustls::HttpsConnector<hyper::client::HttpConnector>;
+
+pub struct FcmPushSender {
+	hub: FirebaseCloudMessaging<Connector>,
+	parent: String
+}
+
+impl FcmPushSender {
+	
+	pub async fn new(key_path: &str, project_id: &str) -> std::io::Result<Self> {
+		
+		let key = oauth2::read_service_account_key(key_path).await?;
+		let auth = oauth2::ServiceAccountAuthenticator::builder(key).build().await?;
+		
+		let connector = hyper_rustls::HttpsConnectorBuilder::new()
+			.with_native_roots()
+			.https_only()
+			.enable_http1()
+			.build();
+		let hub = FirebaseCloudMessaging::new(hyper::Client::builder().build(connector), auth);
+		
+		Ok(Self { hub, parent: format!("projects/{project_id}") })
+		
+	}
+	
+	// Only errors that are definitely about the token. INVALID_ARGUMENT is left
+	// out, since it's also what FCM says about a bad message.
+	fn is_invalid_token(error: &serde_json::Value) -> bool {
+		
+		let error = &error["error"];
+		
+		let unregistered = error["details"]
+			.as_array()
+			.is_some_and(|details| details
+				.iter()
+				.any(|detail| detail["errorCode"].as_str() == Some("UNREGISTERED")));
+		
+		unregistered || error["status"].as_str() == Some("NOT_FOUND")
+		
+	}
+	
+}
+
+#[async_trait]
+impl PushSender for FcmPushSender {
+	
+	async fn send(&self, token: &str, push: &Push) -> Result<(), PushError> {
+		
+		let request = SendMessageRequest {
+			message: Some(Message {
+				token: Some(token.to_string()),
+				notification: Some(Notification {
+					title: Some(push.title.clone()),
+					body: Some(push.body.clone()),
+					..Default::default()
+				}),
+				data: Some(push.data.clone()),
+				..Default::default()
+			}),
+			..Default::default()
+		};
+		
+		let result = self.hub
+			.projects()
+			.messages_send(request, &self.parent)
+			.doit()
+			.await;
+		
+		match result {
+			Ok(_) => Ok(()),
+			Err(google_fcm1::Error::BadRequest(error)) if Self::is_invalid_token(&error) =>
+				Err(PushError::InvalidToken),
+			Err(err) => Err(PushError::Failed(err.to_string()))
+		}
+		
+	}
+	
+}
+
+
+// Used when no FCM credentials are configured
+pub struct LogPushSender;
+
+#[async_trait]
+impl PushSender for LogPushSender {
+	
+	async fn send(&self, _token: &str, push: &Push) -> Result<(), PushError> {
+		info!(title = %push.title, "Push (not sent)");
+		Ok(())
+	}
+	
+}
+
+
+#[derive(Clone)]
+pub struct Notifier {
+	db: DatabaseState,
+	sender: Arc<dyn PushSender>
+}
+
+impl Notifier {
+	
+	pub fn new(db: DatabaseState, sender: Arc<dyn PushSender>) -> Self {
+		Self { db, sender }
+	}
+	
+	// FCM when FCM_SERVICE_ACCOUNT_KEY points at a service account key, only logged otherwise
+	pub async fn from_env(db: DatabaseState, project_id: &str) -> Self {
+		
+		use std::env;
+		
+		let sender: Arc<dyn PushSender> = match env::var("FCM_SERVICE_ACCOUNT_KEY") {
+			Err(_) => {
+				warn!("FCM_SERVICE_ACCOUNT_KEY not set, push notifications disabled");
+				Arc::new(LogPushSender)
+			},
+			Ok(key_path) => match FcmPushSender::new(&key_path, project_id).await {
+				Ok(sender) => Arc::new(sender),
+				Err(err) => {
+					warn!(%err, "Error setting up FCM, push notifications disabled");
+					Arc::new(LogPushSender)
+				}
+			}
+		};
+		
+		Self::new(db, sender)
+		
+	}
+	
+	pub async fn notify(&self, id: &Id, push: Push) {
+		
+		let Ok(tokens) = self.db.get_device_tokens(id).await else {
+			return;
+		};
+		
+		for token in tokens {
+			match self.sender.send(&token, &push).await {
+				Ok(_) => {},
+				Err(PushError::InvalidToken) => {
+					info!(user = %redact(id), "Dropping invalid device token");
+					let _ = self.db.delete_device_token(None, token).await;
+				},
+				Err(PushError::Failed(err)) => warn!(user = %redact(id), %err, "Push error")
+			}
+		}
+		
+	}
+	
+}
+
+
+#[cfg(test)]
+mod tests {
+	
+	use super::*;
+	use crate::models::User;
+	
+	use std::sync::Mutex;
+	use std::collections::HashSet;
+	
+	// Records pushes instead of sending them, and rejects the given tokens
+	#[derive(Default)]
+	struct MemoryPushSender {
+		sent: Mutex<Vec<(String, Push)>>,
+		invalid: HashSet<String>
+	}
+	
+	#[async_trait]
+	impl PushSender for MemoryPushSender {
+		
+		async fn send(&self, token: &str, push: &Push) -> Result<(), PushError> {
+			if self.invalid.contains(token) {
+				return Err(PushError::InvalidToken);
+			}
+			self.sent.lock().unwrap().push((token.to_string(), push.clone()));
+			Ok(())
+		}
+		
+	}
+	
+	#[test]
+	fn push_data_only_has_type_and_ids() {
+		
+		let content = "x".repeat(10_000);
+		let message = OutgoingMessage::ChatMessage {
+			from_id: "alice".to_string(),
+			message_id: "m1".to_string(),
+			timestamp: "2024-06-01 12:00:00".to_string(),
+			content: content.clone()
+		};
+		
+		let push = Push::from_outgoing(&message).unwrap();
+		
+		assert_eq!(push.body.chars().count(), PREVIEW_LENGTH);
+		assert_eq!(push.data.len(), 3);
+		assert_eq!(push.data["type"], "chatMessage");
+		assert_eq!(push.data["fromId"], "alice");
+		assert_eq!(push.data["messageId"], "m1");
+		
+	}
+	
+	#[test]
+	fn match_push_names_the_match() {
+		
+		let viewer = User::new(Id::new("bob".to_string()));
+		let mut matched = User::new(Id::new("alice".to_string()));
+		matched.name = Some("Alice".to_string());
+		
+		let push = Push::from_outgoing(&OutgoingMessage::Match { profile: matched.to_profile(&viewer) }).unwrap();
+		
+		assert_eq!(push.body, "You matched with Alice");
+		assert_eq!(push.data["type"], "match");
+		assert_eq!(push.data["userId"], "alice");
+		
+	}
+	
+	#[test]
+	fn only_some_messages_push() {
+		assert_eq!(Push::from_outgoing(&OutgoingMessage::Like).unwrap().data["type"], "like");
+		assert!(Push::from_outgoing(&OutgoingMessage::Unmatched { from_id: "alice".to_string() }).is_none());
+		assert!(Push::from_outgoing(&OutgoingMessage::Typing { from_id: "alice".to_string(), typing: true }).is_none());
+	}
+	
+	#[test]
+	fn only_unregistered_tokens_are_invalid() {
+		
+		let unregistered = serde_json::json!({ "error": {
+			"status": "INVALID_ARGUMENT",
+			"details": [{ "errorCode": "UNREGISTERED" }]
+		}});
+		let not_found = serde_json::json!({ "error": { "status": "NOT_FOUND" } });
+		let too_big = serde_json::json!({ "error": {
+			"status": "INVALID_ARGUMENT",
+			"details": [{ "errorCode": "INVALID_ARGUMENT" }]
+		}});
+		
+		assert!(FcmPushSender::is_invalid_token(&unregistered));
+		assert!(FcmPushSender::is_invalid_token(&not_found));
+		assert!(!FcmPushSender::is_invalid_token(&too_big));
+		
+	}
+	
+	#[tokio::test]
+	async fn notify_sends_to_every_token_and_drops_invalid_ones() {
+		
+		let db = DatabaseState::temporary();
+		let id = Id::new("alice".to_string());
+		db.read_user(&id).await.unwrap();
+		for token in ["phone", "tablet", "stale"] {
+			db.put_device_token(&id, token.to_string()).await.unwrap();
+		}
+		
+		let sender = Arc::new(MemoryPushSender {
+			invalid: HashSet::from(["stale".to_string()]),
+			..Default::default()
+		});
+		let notifier = Notifier::new(db.clone(), sender.clone());
+		
+		notifier.notify(&id, Push::from_outgoing(&OutgoingMessage::Like).unwrap()).await;
+		
+		let mut sent: Vec<String> = sender.sent.lock().unwrap().iter().map(|(token, _)| token.clone()).collect();
+		sent.sort();
+		assert_eq!(sent, vec!["phone", "tablet"]);
+		
+		let mut remaining = db.get_device_tokens(&id).await.unwrap();
+		remaining.sort();
+		assert_eq!(remaining, vec!["phone", "tablet"]);
+		
+	}
+	
+}
@@ -0,0 +1,210 @@
+use crate::models::User;
+
+use std::collections::HashSet;
+
+
+
+pub const DEFAULT_PAGE_SIZE: usize = 5;
+
+// Candidates loaded per page before ranking, so a refresh never loads every user
+pub const CANDIDATES_PER_PAGE: usize = 20;
+
+pub struct Candidate {
+	pub user: User,
+	// The candidate already liked the viewer (MatchState::Pending from their side)
+	pub liked_viewer: bool
+}
+
+pub trait QueueStrategy: Send + Sync {
+	
+	fn score(&self, viewer: &User, candidate: &Candidate) -> f32;
+	
+	fn page_size(&self) -> usize {
+		DEFAULT_PAGE_SIZE
+	}
+	
+	fn candidate_limit(&self) -> usize {
+		self.page_size() * CANDIDATES_PER_PAGE
+	}
+	
+	fn rank(&self, viewer: &User, candidates: Vec<Candidate>) -> Vec<User> {
+		
+		let mut scored: Vec<(f32, User)> = candidates
+			.into_iter()
+			.map(|candidate| (self.score(viewer, &candidate), candidate.user))
+			.collect();
+		
+		scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+		
+		scored
+			.into_iter()
+			.take(self.page_size())
+			.map(|(_, user)| user)
+			.collect()
+		
+	}
+	
+}
+
+
+pub struct DefaultQueueStrategy {
+	
+	pub page_size: usize,
+	
+	pub liked_weight: f32,
+	pub interests_weight: f32,
+	pub distance_weight: f32,
+	pub completeness_weight: f32
+	
+}
+
+impl Default for DefaultQueueStrategy {
+	fn default() -> Self {
+		Self {
+			page_size: DEFAULT_PAGE_SIZE,
+			liked_weight: 3.0,
+			interests_weight: 1.0,
+			distance_weight: 2.0,
+			completeness_weight: 1.0
+		}
+	}
+}
+
+impl DefaultQueueStrategy {
+	
+	pub fn from_env() -> Self {
+		
+		use std::env;
+		
+		let page_size = env::var("QUEUE_PAGE_SIZE")
+			.ok()
+			.and_then(|size| size.parse().ok())
+			.unwrap_or(DEFAULT_PAGE_SIZE);
+		
+		Self { page_size, ..Default::default() }
+		
+	}
+	
+	fn interests(user: &User) -> HashSet<String> {
+		
+		user.interests
+			.as_deref()
+			.unwrap_or_default()
+			.split(',')
+			.map(|interest| interest.trim().to_lowercase())
+			.filter(|interest| !interest.is_empty())
+			.collect()
+		
+	}
+	
+	// Fraction of shared interests, relative to the viewer's
+	fn interest_overlap(viewer: &User, candidate: &User) -> f32 {
+		
+		let (ours, theirs) = (Self::interests(viewer), Self::interests(candidate));
+		
+		if ours.is_empty() {
+			0.0
+		} else {
+			ours.intersection(&theirs).count() as f32 / ours.len() as f32
+		}
+		
+	}
+	
+	// 1.0 when right next to each other, halving every 25km
+	fn proximity(viewer: &User, candidate: &User) -> f32 {
+		match viewer.distance_to_user(candidate) {
+			Some(distance) => 0.5f32.powf(distance as f32 / 25.0),
+			None => 0.0
+		}
+	}
+	
+	fn completeness(user: &User) -> f32 {
+		
+		let fields = [
+			user.name.is_some(),
+			user.birth_date.is_some(),
+			user.gender_identity.is_some(),
+			user.pronouns.is_some(),
+			user.bio.is_some(),
+			user.looking_for.is_some(),
+			user.interests.is_some(),
+			user.photos.is_some()
+		];
+		
+		fields.iter().filter(|&&filled| filled).count() as f32 / fields.len() as f32
+		
+	}
+	
+}
+
+impl QueueStrategy for DefaultQueueStrategy {
+	
+	fn score(&self, viewer: &User, candidate: &Candidate) -> f32 {
+		
+		let liked = if candidate.liked_viewer { 1.0 } else { 0.0 };
+		
+		self.liked_weight * liked
+			+ self.interests_weight * Self::interest_overlap(viewer, &candidate.user)
+			+ self.distance_weight * Self::proximity(viewer, &candidate.user)
+			+ self.completeness_weight * Self::completeness(&candidate.user)
+		
+	}
+	
+	fn page_size(&self) -> usize {
+		self.page_size
+	}
+	
+}
+
+
+#[cfg(test)]
+mod tests {
+	
+	use super::*;
+	use crate::Id;
+	
+	fn candidate(id: &str, liked_viewer: bool, interests: Option<&str>) -> Candidate {
+		let mut user = User::new(Id::new(id.to_string()));
+		user.interests = interests.map(str::to_string);
+		Candidate { user, liked_viewer }
+	}
+	
+	fn ids(users: Vec<User>) -> Vec<String> {
+		users.into_iter().map(|user| user.id).collect()
+	}
+	
+	#[test]
+	fn rank_orders_by_score() {
+		
+		let strategy = DefaultQueueStrategy::default();
+		let mut viewer = User::new(Id::new("viewer".to_string()));
+		viewer.interests = Some("climbing, chess".to_string());
+		
+		let ranked = strategy.rank(&viewer, vec![
+			candidate("stranger", false, None),
+			candidate("shared", false, Some("Chess, cooking")),
+			candidate("liked", true, None)
+		]);
+		
+		assert_eq!(ids(ranked), vec!["liked", "shared", "stranger"]);
+		
+	}
+	
+	#[test]
+	fn rank_truncates_to_page_size() {
+		
+		let strategy = DefaultQueueStrategy { page_size: 2, ..Default::default() };
+		let viewer = User::new(Id::new("viewer".to_string()));
+		
+		let candidates = (0..5)
+			.map(|index| candidate(&index.to_string(), index == 3, None))
+			.collect();
+		let ranked = strategy.rank(&viewer, candidates);
+		
+		assert_eq!(ranked.len(), 2);
+		assert_eq!(ranked[0].id, "3");
+		assert_eq!(strategy.candidate_limit(), 2 * CANDIDATES_PER_PAGE);
+		
+	}
+	
+}
@@ -1,5 +1,21 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    blocks (blocker, blocked) {
+        blocker -> Text,
+        blocked -> Text,
+        timestamp -> Text,
+    }
+}
+
+diesel::table! {
+    device_tokens (token) {
+        token -> Text,
+        user_id -> Text,
+        timestamp -> Text,
+    }
+}
+
 diesel::table! {
     matches (user1, user2) {
         user1 -> Text,
@@ -16,6 +32,39 @@ diesel::table! {
         sender -> Integer,
         timestamp -> Text,
         content -> Text,
+        seq -> Integer,
+    }
+}
+
+diesel::table! {
+    outbox (seq) {
+        seq -> Integer,
+        user_id -> Text,
+        payload -> Text,
+        timestamp -> Text,
+    }
+}
+
+diesel::table! {
+    read_markers (user1, user2, reader) {
+        user1 -> Text,
+        user2 -> Text,
+        reader -> Integer,
+        message_id -> Text,
+        timestamp -> Text,
+        seq -> Integer,
+    }
+}
+
+diesel::table! {
+    reports (id) {
+        id -> Integer,
+        reporter -> Text,
+        reported -> Text,
+        reason -> Text,
+        details -> Nullable<Text>,
+        message_ids -> Nullable<Text>,
+        timestamp -> Text,
     }
 }
 
@@ -32,11 +81,22 @@ diesel::table! {
         looking_for -> Nullable<Text>,
         interests -> Nullable<Text>,
         photos -> Nullable<Text>,
+        age_min -> Nullable<Integer>,
+        age_max -> Nullable<Integer>,
+        max_distance -> Nullable<Integer>,
+        seeking_genders -> Nullable<Text>,
+        geohash -> Nullable<Text>,
+        last_seen -> Nullable<Text>,
     }
 }
 
 diesel::allow_tables_to_appear_in_same_query!(
+    blocks,
+    device_tokens,
     matches,
     messages,
+    outbox,
+    read_markers,
+    reports,
     users,
 );
@@ -2,8 +2,14 @@
 
 
 use crate::Id;
+use crate::id::redact;
 
+use crate::db::{DatabaseState, DbError};
 use crate::models::Profile;
+use crate::http::ChatHistoryPage;
+use crate::push::{Notifier, Push};
+use crate::protocol::Protocol;
+use crate::metrics::MetricsState;
 
 pub use axum::extract::ws::{
 	WebSocketUpgrade,
@@ -14,9 +20,13 @@ pub use axum::extract::ws::{
 
 
 use std::sync::Arc;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
 use dashmap::DashMap;
-
-//use std::future::Future;
+use uuid::Uuid;
+use tokio::sync::{mpsc, Mutex, OwnedMutexGuard};
+use tracing::{info, warn, debug, instrument, Instrument, Span};
 
 use serde::{Serialize, Deserialize};
 
@@ -41,7 +51,35 @@ pub enum IncomingMessage {
 	QueueRefresh { blacklist: Option<Vec<String>> },
 	
 	Impression { to_id: String, liked: bool },
-	ChatMessage { to_id: String, content: String }
+	// `nonce` is echoed back in ChatAck/ChatFailed so the client can match up its local copy
+	ChatMessage { to_id: String, content: String, nonce: Option<String> },
+	ChatHistory { with_id: String, before: Option<String>, limit: Option<i64> },
+	MarkRead { match_id: String, up_to: String },
+	Typing { to_id: String, typing: bool },
+	
+	// Confirms receipt of every Outbox event up to and including `seq`
+	OutboxAck { seq: i32 },
+	
+	Unmatch { to_id: String },
+	Report { to_id: String, reason: String, details: Option<String>, message_ids: Option<Vec<String>> }
+	
+}
+impl IncomingMessage {
+	
+	// Same as the `type` tag
+	pub fn kind(&self) -> &'static str {
+		match self {
+			IncomingMessage::QueueRefresh { .. } => "queueRefresh",
+			IncomingMessage::Impression { .. } => "impression",
+			IncomingMessage::ChatMessage { .. } => "chatMessage",
+			IncomingMessage::ChatHistory { .. } => "chatHistory",
+			IncomingMessage::MarkRead { .. } => "markRead",
+			IncomingMessage::Typing { .. } => "typing",
+			IncomingMessage::OutboxAck { .. } => "outboxAck",
+			IncomingMessage::Unmatch { .. } => "unmatch",
+			IncomingMessage::Report { .. } => "report"
+		}
+	}
 	
 }
 
@@ -54,65 +92,94 @@ pub enum OutgoingMessage {
 	
 	Like,
 	Match { #[serde(flatten)] profile: Profile },
-	ChatMessage { from_id: String, message_id: String, content: String }
-}
-
-
-/*#[derive(Debug)]
-#[derive(Deserialize)]
-#[serde(tag = "type", rename_all = "camelCase", rename_all_fields = "camelCase")]
-pub enum IncomingRequest {
+	ChatMessage { from_id: String, message_id: String, timestamp: String, content: String },
+	ChatHistory { with_id: String, #[serde(flatten)] page: ChatHistoryPage },
+	Read { from_id: String, up_to: String },
+	Typing { from_id: String, typing: bool },
+	Presence { user_id: String, online: bool },
+	Unmatched { from_id: String },
 	
-	Discovery { blacklist: Vec<String> }
+	ChatAck { nonce: Option<String>, message_id: String, timestamp: String },
+	ChatFailed { nonce: Option<String>, to_id: String, reason: ChatFailure },
+	// Any other handler failure. Carries its own `request_id`, since it's also
+	// sent for messages too malformed to have been read into an envelope.
+	Error { request_id: Option<String>, code: ErrorCode, message: String },
 	
+	// A durable event, kept until the client acks its seq
+	Outbox { seq: i32, event: serde_json::Value }
 }
-
-#[derive(Debug)]
-#[derive(Deserialize)]
-#[serde(tag = "type", rename_all = "camelCase", rename_all_fields = "camelCase")]
-pub enum IncomingEvent {
+impl OutgoingMessage {
 	
-	Impression { to_id: String, liked: bool },
-	ChatMessage { to_id: String, content: String }
+	// Same as the `type` tag
+	pub fn kind(&self) -> &'static str {
+		match self {
+			OutgoingMessage::QueueRefresh { .. } => "queueRefresh",
+			OutgoingMessage::Like => "like",
+			OutgoingMessage::Match { .. } => "match",
+			OutgoingMessage::ChatMessage { .. } => "chatMessage",
+			OutgoingMessage::ChatHistory { .. } => "chatHistory",
+			OutgoingMessage::Read { .. } => "read",
+			OutgoingMessage::Typing { .. } => "typing",
+			OutgoingMessage::Presence { .. } => "presence",
+			OutgoingMessage::Unmatched { .. } => "unmatched",
+			OutgoingMessage::ChatAck { .. } => "chatAck",
+			OutgoingMessage::ChatFailed { .. } => "chatFailed",
+			OutgoingMessage::Error { .. } => "error",
+			OutgoingMessage::Outbox { .. } => "outbox"
+		}
+	}
+	
+	// Events that have to reach the user eventually, even if the socket dies before they're read
+	pub fn is_durable(&self) -> bool {
+		matches!(self,
+			OutgoingMessage::Like |
+			OutgoingMessage::Match { .. } |
+			OutgoingMessage::ChatMessage { .. })
+	}
 	
 }
 
-#[derive(Debug)]
 #[derive(Deserialize)]
-#[serde(tag = "type", rename_all = "camelCase", rename_all_fields = "camelCase")]
-pub enum Incoming {
-	
-	Request(IncomingRequest),
-	Event(IncomingEvent)
-	
+#[serde(rename_all = "camelCase")]
+pub struct WebSocketQuery {
+	// Last Outbox seq the client has processed, if reconnecting
+	pub last_seq: Option<i32>,
+	// For clients that can't set Sec-WebSocket-Protocol
+	pub version: Option<u32>,
+	pub encoding: Option<String>
 }
 
-
 #[derive(Debug)]
 #[derive(Serialize)]
-#[serde(tag = "type", rename_all = "camelCase", rename_all_fields = "camelCase")]
-pub enum OutgoingResponse {
-	Like,
-	Match { #[serde(flatten)] profile: Profile },
-	ChatMessage { from_id: String, message_id: String, content: String }
+#[serde(rename_all = "camelCase")]
+pub enum ChatFailure {
+	NotMatched,
+	ServerError
 }
 
 #[derive(Debug)]
 #[derive(Serialize)]
-#[serde(tag = "type", rename_all = "camelCase", rename_all_fields = "camelCase")]
-pub enum OutgoingEvent {
-	Like,
-	Match { #[serde(flatten)] profile: Profile },
-	ChatMessage { from_id: String, message_id: String, content: String }
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+	InvalidMessage,
+	NotFound,
+	NotMatched,
+	Conflict,
+	// Temporarily overloaded; worth retrying
+	Unavailable,
+	ServerError
+}
+impl From<&DbError> for ErrorCode {
+	fn from(err: &DbError) -> Self {
+		match err {
+			DbError::NotFound => ErrorCode::NotFound,
+			DbError::ConstraintViolation(_) => ErrorCode::Conflict,
+			DbError::PoolExhausted => ErrorCode::Unavailable,
+			DbError::Serialization(_) | DbError::Query(_) => ErrorCode::ServerError
+		}
+	}
 }
 
-#[derive(Debug)]
-#[derive(Serialize)]
-#[serde(tag = "type", rename_all = "camelCase", rename_all_fields = "camelCase")]
-pub enum Outgoing {
-	Response(OutgoingResponse),
-	Event(OutgoingEvent)
-}*/
 
 use futures_util::{
 	sink::SinkExt,
@@ -121,105 +188,236 @@ use futures_util::{
 pub type WebSocketReceiver = SplitStream<WebSocket>;
 pub type WebSocketSender = SplitSink<WebSocket, Message>;
 
+// Minimum gap between relayed typing indicators from one sender to one recipient
+const TYPING_INTERVAL: Duration = Duration::from_secs(1);
+// Typing frames one sender can have checked per TYPING_INTERVAL, across all their matches
+const TYPING_FRAMES: u32 = 5;
+
+// Identifies one connection, so a user can be online from several devices at once
+pub type SessionId = Uuid;
+
+// Messages buffered per session before it's considered too slow and disconnected
+const CLIENT_QUEUE_SIZE: usize = 64;
+
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+// Sessions that haven't sent anything (pongs included) for this long are dropped
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
 #[derive(Clone)]
 pub struct WebSocketState {
-	clients: Arc<DashMap<Id, Client>>
+	clients: Arc<DashMap<Id, Vec<Client>>>,
+	// By sender
+	typing: Arc<DashMap<Id, TypingLimit>>,
+	// Held while storing and fanning out a durable event, or while replaying to a
+	// new session, so events neither slip between replay and registration nor overtake each other
+	outbox_locks: Arc<DashMap<Id, Arc<Mutex<()>>>>,
+	idle_timeout: Duration,
+	// Fallbacks for users that aren't connected
+	db: DatabaseState,
+	notifier: Notifier,
+	metrics: MetricsState
+}
+
+
+#[derive(Default)]
+struct TypingLimit {
+	window_start: Option<Instant>,
+	frames: u32,
+	// By recipient
+	last_sent: HashMap<Id, Instant>
 }
 
 
 struct Client {
-	sender: WebSocketSender
+	session: SessionId,
+	// Sessions of the same user can be on different app versions and encodings
+	protocol: Protocol,
+	// Drained by the session's writer task, so nothing awaits socket I/O under a map lock
+	queue: mpsc::Sender<Message>
 }
 
 impl Client {
 	
-	async fn send(&mut self, message: Message) -> Result<(), ()> {
-		
-		let result = self.sender.send(message).await;
+	// Owns the socket's sending half. Ends, closing the socket, once the session
+	// is dropped from `clients` or the socket errors.
+	async fn write(mut sender: WebSocketSender, mut queue: mpsc::Receiver<Message>) {
 		
-		match result {
-			Ok(_) => Ok(()),
-			Err(err) => {
-				dbg!(err);
-				//sender.close().await;
-				Err(())
+		while let Some(message) = queue.recv().await {
+			if let Err(err) = sender.send(message).await {
+				debug!(%err, "WebSocket sender error");
+				break;
 			}
 		}
 		
+		let _ = sender.close().await;
+		
 	}
-	/*
-	fn send_all(&self, message: impl Iterator<Message>) {
+	
+}
+
+
+// Where replies to one incoming message go: the session it came from, tagged with its `requestId`
+#[derive(Clone)]
+pub struct Reply {
+	ws: WebSocketState,
+	id: Id,
+	session: SessionId,
+	request_id: Option<String>
+}
+
+impl Reply {
+	
+	pub async fn send(&self, message: OutgoingMessage) -> Option<()> {
+		self.ws.send_session(&self.id, self.session, &message, self.request_id.as_deref()).await.ok()
+	}
+	
+	pub async fn error(&self, code: ErrorCode, message: impl Into<String>) -> Option<()> {
+		
+		let error = OutgoingMessage::Error {
+			request_id: self.request_id.clone(),
+			code,
+			message: message.into()
+		};
 		
-		let result = self.sender.send_all()
+		self.ws.send_session(&self.id, self.session, &error, None).await.ok()
 		
 	}
-	*/
+	// Details stay in the server logs; the client only gets the code
+	pub async fn db_error(&self, err: &DbError, message: impl Into<String>) -> Option<()> {
+		self.error(err.into(), message).await
+	}
 	
 }
 
 
 impl WebSocketState {
 	
-	pub fn new() -> Self {
+	pub fn new(db: DatabaseState, notifier: Notifier, metrics: MetricsState) -> Self {
 		Self {
 			clients: Arc::new(DashMap::new()),
+			typing: Arc::new(DashMap::new()),
+			outbox_locks: Arc::new(DashMap::new()),
+			idle_timeout: Self::idle_timeout_from_env(),
+			db,
+			notifier,
+			metrics
 		}
 	}
 	
-	pub async fn to_incoming(message: Result<Message, axum::Error>) -> Option<IncomingMessage> {
+	pub fn metrics(&self) -> &MetricsState {
+		&self.metrics
+	}
+	
+	// Connections come and go constantly, so they're counted when scraped
+	pub fn record_connections(&self) {
 		
-		match message {
-			Ok(Message::Text(data)) => {
-				
-				let result = serde_json::from_str(&data);
-					
-				match result {
-					Err(err) => println!("IncomingMessage deserialization error: {}", err),
-					Ok(message) => return Some(message)
-				}
-				
-			},
-			Ok(Message::Close(_)) => {},
-			Ok(message) => println!("Invalid message received: {:?}", message),
-			Err(err) => println!("WebSocket receiver error: {}", err)
-		}
+		let sessions: usize = self.clients.iter().map(|entry| entry.value().len()).sum();
 		
-		None
+		self.metrics.connected_users.set(self.clients.len() as i64);
+		self.metrics.connected_sessions.set(sessions as i64);
 		
 	}
 	
-	pub async fn listen<F>(&self, id: Id, socket: WebSocket, on_message: F)
-		where F: Fn(IncomingMessage)
+	// At least three ticks per idle timeout, so sessions are dropped close to when it runs out
+	fn heartbeat_interval(&self) -> Duration {
+		PING_INTERVAL.min(self.idle_timeout / 3).max(Duration::from_secs(1))
+	}
+	
+	// WS_IDLE_TIMEOUT in seconds
+	fn idle_timeout_from_env() -> Duration {
+		
+		use std::env;
+		
+		env::var("WS_IDLE_TIMEOUT")
+			.ok()
+			.and_then(|seconds| seconds.parse().ok())
+			.map(Duration::from_secs)
+			.unwrap_or(DEFAULT_IDLE_TIMEOUT)
+		
+	}
+	
+	// Handlers run as their own tasks, inside this session's span
+	#[instrument(name = "session", skip_all, fields(user = %redact(&id), session = tracing::field::Empty))]
+	pub async fn listen<F, Fut>(&self, id: Id, socket: WebSocket, protocol: Protocol, last_seq: Option<i32>, on_message: F)
+		where F: Fn(IncomingMessage, Reply) -> Fut, Fut: Future<Output = ()> + Send + 'static
 	{
 		
 		let (sender, mut receiver) = socket.split();
-		let _old_client = self.clients.insert(id.clone(), Client { sender });
+		let (queue, queued) = mpsc::channel(CLIENT_QUEUE_SIZE);
+		tokio::spawn(Client::write(sender, queued).in_current_span());
 		
-		/*if let Some(old) = old {
-			// Do something? Does this matter?
-		}*/
+		let session = Uuid::new_v4();
+		Span::current().record("session", tracing::field::display(session));
+		info!(?protocol, "WebSocket session opened");
 		
-		while let Some(message) = receiver.next().await {
-			
-			match message {
-				Ok(Message::Text(data)) => {
+		// Live events only reach the session once it's caught up
+		let outbox = self.lock_outbox(&id).await;
+		self.replay_outbox(&id, &queue, protocol, last_seq).await;
+		self.clients.entry(id.clone()).or_default().push(Client { session, protocol, queue });
+		self.unlock_outbox(&id, outbox);
+		
+		let mut heartbeat = tokio::time::interval(self.heartbeat_interval());
+		let mut last_active = Instant::now();
+		
+		loop {
+			tokio::select! {
+				message = receiver.next() => {
+					
+					let Some(message) = message else {
+						break;
+					};
+					last_active = Instant::now();
 					
-					let result = serde_json::from_str::<IncomingMessage>(&data);
-						
-					match result {
-						Err(err) => println!("IncomingMessage deserialization error: {}", err),
-						Ok(message) => on_message(message)
+					match message {
+						Ok(frame @ (Message::Text(_) | Message::Binary(_))) => {
+							
+							let result = protocol.decode(&frame);
+							
+							match result {
+								Err((request_id, err)) => {
+									warn!(%err, "IncomingMessage deserialization error");
+									self.reply(&id, session, request_id)
+										.error(ErrorCode::InvalidMessage, err.to_string()).await;
+								},
+								Ok(envelope) => {
+									self.metrics.messages.with_label_values(&[envelope.message.kind()]).inc();
+									let handler = on_message(envelope.message, self.reply(&id, session, envelope.request_id));
+									tokio::spawn(handler.in_current_span());
+								}
+							}
+							
+						},
+						// Pings are answered by axum itself; both just count as activity
+						Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => {},
+						Ok(Message::Close(frame)) => {
+							let _ = self.queue_session(&id, session, Message::Close(frame));
+							break;
+						},
+						Err(err) => {
+							debug!(%err, "WebSocket receiver error");
+							break;
+						}
 					}
 					
 				},
-				Ok(Message::Close(_)) => {},
-				Ok(message) => println!("Invalid message received: {:?}", message),
-				Err(err) => println!("WebSocket receiver error: {}", err)
+				_ = heartbeat.tick() => {
+					
+					if last_active.elapsed() >= self.idle_timeout {
+						info!("WebSocket session timed out");
+						break;
+					}
+					
+					// Already dropped, e.g. for stalling
+					if self.queue_session(&id, session, Message::Ping(Vec::new())).is_none() {
+						break;
+					}
+					
+				}
 			}
-			
 		}
 		
-		self.drop_client(&id).await
+		info!("WebSocket session closed");
+		self.drop_client(&id, session).await
 		
 	}
 	
@@ -240,52 +438,248 @@ impl WebSocketState {
 		receiver
 		
 	}*/
-	pub async fn drop_client(&self, id: &Id) {
-		self.clients.remove(id);
+	// Sends everything queued while the user was away that they haven't confirmed yet
+	async fn replay_outbox(&self, id: &Id, queue: &mpsc::Sender<Message>, protocol: Protocol, last_seq: Option<i32>) {
+		
+		if let Some(last_seq) = last_seq {
+			let _ = self.db.ack_outbox(id, last_seq).await;
+		}
+		
+		let Ok(events) = self.db.get_outbox(id).await else {
+			return;
+		};
+		
+		let mut replayed = None;
+		for (seq, payload) in events {
+			
+			let message = match serde_json::from_str(&payload) {
+				Ok(event) => OutgoingMessage::Outbox { seq, event },
+				Err(err) => {
+					warn!(seq, %err, "Outbox deserialization error");
+					continue;
+				}
+			};
+			if !protocol.supports(&message) {
+				continue;
+			}
+			
+			match protocol.encode(&message, None) {
+				Ok(frame) => {
+					if queue.send(frame).await.is_err() {
+						return;
+					}
+					replayed = Some(seq);
+				},
+				Err(err) => warn!(seq, %err, "OutgoingMessage serialization error")
+			}
+			
+		}
+		
+		// Clients that can't ack only get the one replay
+		if let (false, Some(seq)) = (protocol.acknowledges_outbox(), replayed) {
+			let _ = self.db.ack_outbox(id, seq).await;
+		}
+		
+	}
+	
+	async fn lock_outbox(&self, id: &Id) -> OwnedMutexGuard<()> {
+		let lock = self.outbox_locks.entry(id.clone()).or_default().clone();
+		lock.lock_owned().await
+	}
+	fn unlock_outbox(&self, id: &Id, guard: OwnedMutexGuard<()>) {
+		
+		drop(guard);
+		
+		// Only the map's own reference left, so nobody is waiting on it
+		self.outbox_locks.remove_if(id, |_, lock| Arc::strong_count(lock) == 1);
+		
+	}
+	
+	// Removes only the given session; the user stays online while any others remain
+	pub async fn drop_client(&self, id: &Id, session: SessionId) {
+		
+		self.clients.remove_if_mut(id, |_, sessions| {
+			sessions.retain(|client| client.session != session);
+			sessions.is_empty()
+		});
+		
+		if !self.clients.contains_key(id) {
+			self.typing.remove(id);
+		}
+		
+	}
+	
+	// Per sender, before anything costs a query. Counts stops too, so they can't be used to flood.
+	pub fn allow_typing_frame(&self, from_id: &Id) -> bool {
+		
+		let now = Instant::now();
+		let mut limit = self.typing.entry(from_id.clone()).or_default();
+		
+		if limit.window_start.is_none_or(|start| now.duration_since(start) >= TYPING_INTERVAL) {
+			limit.window_start = Some(now);
+			limit.frames = 0;
+		}
+		limit.frames += 1;
+		
+		limit.frames <= TYPING_FRAMES
+		
+	}
+	// Rate limits typing indicators per conversation. Stopping is always let
+	// through, so an indicator can't get stuck on the other end.
+	pub fn allow_typing(&self, from_id: &Id, to_id: &Id, typing: bool) -> bool {
+		
+		let now = Instant::now();
+		let mut limit = self.typing.entry(from_id.clone()).or_default();
+		
+		let allowed = !typing || limit.last_sent
+			.get(to_id)
+			.is_none_or(|last| now.duration_since(*last) >= TYPING_INTERVAL);
+		if allowed {
+			limit.last_sent.insert(to_id.clone(), now);
+		}
+		
+		allowed
+		
 	}
 	
 	pub async fn has_id(&self, id: &Id) -> bool {
 		self.clients.contains_key(id)
 	}
 	
+	fn reply(&self, id: &Id, session: SessionId, request_id: Option<String>) -> Reply {
+		Reply { ws: self.clone(), id: id.clone(), session, request_id }
+	}
+	
+	// Sends to a single session, without falling back to the outbox.
+	// Waits for room in the session's queue rather than disconnecting it.
+	async fn send_session(&self, id: &Id, session: SessionId, message: &OutgoingMessage, request_id: Option<&str>) -> Result<(), ()> {
+		
+		let (queue, protocol) = self.session_queue(id, session).ok_or(())?;
+		
+		if !protocol.supports(message) {
+			return Err(());
+		}
+		
+		let frame = protocol.encode(message, request_id).map_err(|err| {
+			warn!(%err, "OutgoingMessage serialization error");
+		})?;
+		
+		queue.send(frame).await.map_err(|_| ())
+		
+	}
+	// None if the session is gone; a full queue still counts as connected
+	fn queue_session(&self, id: &Id, session: SessionId, message: Message) -> Option<()> {
+		
+		let (queue, _) = self.session_queue(id, session)?;
+		
+		match queue.try_send(message) {
+			Err(mpsc::error::TrySendError::Closed(_)) => None,
+			_ => Some(())
+		}
+		
+	}
+	fn session_queue(&self, id: &Id, session: SessionId) -> Option<(mpsc::Sender<Message>, Protocol)> {
+		self.clients
+			.get(id)?
+			.iter()
+			.find(|client| client.session == session)
+			.map(|client| (client.queue.clone(), client.protocol))
+	}
+	
+	// Sends to every session of the user that understands the message; delivered
+	// if any of them got it. Durable events go through the outbox, and get a push
+	// notification if no session took them.
 	pub async fn try_send(&self, id: &Id, message: OutgoingMessage) -> Option<Result<(), ()>> {
 		
-		match self.clients.get_mut(id) {
-			None => None,
-			Some(mut client) => {
-				
-				let result = serde_json::to_string(&message);
-			
-				match result {
-					Err(err) => {
-						dbg!(err);
-						Some(Err(()))
-					},
-					Ok(data) =>
-						Some(client.send(Message::Text(data)).await)
-				}
-				
+		if !message.is_durable() {
+			let delivered = self.fan_out(id, &message).await;
+			return delivered.map(|protocols| if protocols.is_empty() { Err(()) } else { Ok(()) });
+		}
+		
+		let outbox = self.lock_outbox(id).await;
+		let delivered = self.send_durable(id, &message).await;
+		self.unlock_outbox(id, outbox);
+		
+		let result = delivered.map(|protocols| if protocols.is_empty() { Err(()) } else { Ok(()) });
+		
+		if !matches!(result, Some(Ok(_))) {
+			if let Some(push) = Push::from_outgoing(&message) {
+				let (notifier, id) = (self.notifier.clone(), id.clone());
+				tokio::spawn(async move {
+					notifier.notify(&id, push).await;
+				}.in_current_span());
 			}
 		}
 		
+		result
+		
 	}
-	pub async fn send_soft(&self, id: &Id, message: OutgoingMessage) -> Option<()> {
-		match self.try_send(id, message).await {
-			Some(Ok(_)) => Some(()),
-			_ => None
+	// Stored before it's sent, so it's only gone once a client acks it
+	async fn send_durable(&self, id: &Id, message: &OutgoingMessage) -> Option<Vec<Protocol>> {
+		
+		let stored = Protocol::store(message).map_err(|err| {
+			warn!(%err, "Outbox serialization error");
+		});
+		let seq = match &stored {
+			Ok(event) => self.db.put_outbox(id, event.to_string()).await.ok(),
+			Err(_) => None
+		};
+		
+		// Still worth a live attempt if it couldn't be stored
+		let (Ok(event), Some(seq)) = (stored, seq) else {
+			return self.fan_out(id, message).await;
+		};
+		
+		let delivered = self.fan_out(id, &OutgoingMessage::Outbox { seq, event }).await;
+		
+		// Clients that can't ack only get the one delivery
+		if let Some(protocols) = &delivered {
+			if !protocols.is_empty() && !protocols.iter().any(Protocol::acknowledges_outbox) {
+				let _ = self.db.ack_outbox(id, seq).await;
+			}
 		}
+		
+		delivered
+		
 	}
-	pub async fn send(&self, id: &Id, message: OutgoingMessage) -> Option<()> {
+	// Protocols of the sessions that got the message, or None if the user isn't
+	// connected. Sessions whose queue is full are too slow to keep up, and get disconnected.
+	async fn fan_out(&self, id: &Id, message: &OutgoingMessage) -> Option<Vec<Protocol>> {
 		
-		match self.try_send(id, message).await {
-			Some(Ok(_)) => Some(()),
-			Some(Err(_)) => None,
-			_ => {
-				println!("WebSocket send to invalid client [{}]", id);
-				None
+		let mut stalled = Vec::new();
+		
+		let delivered = self.clients.get(id).map(|sessions| {
+			
+			let mut delivered = Vec::new();
+			
+			for client in sessions.iter().filter(|client| client.protocol.supports(message)) {
+				
+				let frame = match client.protocol.encode(message, None) {
+					Ok(frame) => frame,
+					Err(err) => {
+						warn!(%err, "OutgoingMessage serialization error");
+						continue;
+					}
+				};
+				
+				match client.queue.try_send(frame) {
+					Ok(_) => delivered.push(client.protocol),
+					Err(_) => stalled.push(client.session)
+				}
+				
 			}
+			
+			delivered
+			
+		});
+		
+		for session in stalled {
+			warn!(user = %redact(id), %session, "Disconnecting stalled WebSocket session");
+			self.drop_client(id, session).await;
 		}
 		
+		delivered
+		
 	}
 	
 }
@@ -300,3 +694,111 @@ impl WebSocketState {
 
 
 
+
+
+#[cfg(test)]
+mod tests {
+	
+	use super::*;
+	use crate::push::LogPushSender;
+	
+	fn state() -> WebSocketState {
+		let db = DatabaseState::temporary();
+		let notifier = Notifier::new(db.clone(), Arc::new(LogPushSender));
+		WebSocketState::new(db, notifier, MetricsState::new())
+	}
+	
+	#[tokio::test]
+	async fn typing_is_limited_per_conversation() {
+		
+		let ws = state();
+		let (alice, bob, carol) = (Id::new("alice".to_string()), Id::new("bob".to_string()), Id::new("carol".to_string()));
+		
+		assert!(ws.allow_typing(&alice, &bob, true));
+		assert!(!ws.allow_typing(&alice, &bob, true));
+		// Other conversations have their own limit
+		assert!(ws.allow_typing(&alice, &carol, true));
+		assert!(ws.allow_typing(&bob, &alice, true));
+		// Stopping always goes through
+		assert!(ws.allow_typing(&alice, &bob, false));
+		
+	}
+	
+	#[tokio::test]
+	async fn heartbeat_keeps_up_with_the_idle_timeout() {
+		
+		let ws = state();
+		assert_eq!(ws.heartbeat_interval(), PING_INTERVAL);
+		
+		let ws = WebSocketState { idle_timeout: Duration::from_secs(10), ..state() };
+		assert!(ws.heartbeat_interval() <= Duration::from_secs(4));
+		
+		let ws = WebSocketState { idle_timeout: Duration::ZERO, ..state() };
+		assert_eq!(ws.heartbeat_interval(), Duration::from_secs(1));
+		
+	}
+	
+	#[tokio::test]
+	async fn typing_frames_are_limited_per_sender() {
+		
+		let ws = state();
+		let (alice, bob) = (Id::new("alice".to_string()), Id::new("bob".to_string()));
+		
+		for _ in 0..TYPING_FRAMES {
+			assert!(ws.allow_typing_frame(&alice));
+		}
+		assert!(!ws.allow_typing_frame(&alice));
+		assert!(ws.allow_typing_frame(&bob));
+		
+	}
+	
+	fn connect(ws: &WebSocketState, id: &Id, protocol: Protocol) -> mpsc::Receiver<Message> {
+		let (queue, queued) = mpsc::channel(CLIENT_QUEUE_SIZE);
+		ws.clients.entry(id.clone()).or_default().push(Client { session: Uuid::new_v4(), protocol, queue });
+		queued
+	}
+	
+	#[tokio::test]
+	async fn durable_events_are_kept_until_acked() {
+		
+		use crate::protocol::{ProtocolVersion, Encoding};
+		
+		let ws = state();
+		let alice = Id::new("alice".to_string());
+		let mut queued = connect(&ws, &alice, Protocol { version: ProtocolVersion::V1, encoding: Encoding::Json });
+		
+		assert!(matches!(ws.try_send(&alice, OutgoingMessage::Like).await, Some(Ok(()))));
+		
+		let Some(Message::Text(frame)) = queued.recv().await else {
+			panic!("expected a text frame");
+		};
+		let frame: serde_json::Value = serde_json::from_str(&frame).unwrap();
+		assert_eq!(frame["type"], "outbox");
+		assert_eq!(frame["event"]["type"], "like");
+		
+		// Queued for the socket isn't delivered; only the ack drops it
+		let outbox = ws.db.get_outbox(&alice).await.unwrap();
+		assert_eq!(outbox.len(), 1);
+		assert_eq!(frame["seq"], outbox[0].0);
+		
+		// Not kept at all
+		ws.try_send(&alice, OutgoingMessage::Typing { from_id: "bob".to_string(), typing: true }).await;
+		assert_eq!(ws.db.get_outbox(&alice).await.unwrap().len(), 1);
+		
+	}
+	
+	#[tokio::test]
+	async fn legacy_deliveries_are_acked_for_the_client() {
+		
+		let ws = state();
+		let alice = Id::new("alice".to_string());
+		let mut queued = connect(&ws, &alice, Protocol::DEFAULT);
+		
+		ws.try_send(&alice, OutgoingMessage::Like).await;
+		
+		assert!(queued.recv().await.is_some());
+		assert!(ws.db.get_outbox(&alice).await.unwrap().is_empty());
+		
+	}
+	
+}